@@ -0,0 +1,54 @@
+// the tokens a firmware frames its responses with, the driver code is written against ESP-AT
+// and asks the active dialect wherever another firmware may word things differently
+pub trait Dialect {
+    // ends a successful response
+    fn success_terminator(&self) -> &'static str {
+        "OK"
+    }
+
+    // end a failed response, each 8 bytes at most. ESP-AT ends every rejected or unknown command
+    // with ERROR, which is how callers tell firmware lacking a command (Unsupported) from a timeout
+    fn error_tokens(&self) -> &'static [&'static str] {
+        &["ERROR", "Error"]
+    }
+
+    // asks for the payload after a send command
+    fn prompt(&self) -> &'static str {
+        ">"
+    }
+
+    // starts an incoming data frame, followed by [<id>,]<len>:<data>
+    fn ipd_format(&self) -> &'static str {
+        "+IPD,"
+    }
+
+    // printed once the firmware finished booting after a reset
+    fn ready(&self) -> &'static str {
+        "ready"
+    }
+
+    // the module took the payload of a send command
+    fn send_ok(&self) -> &'static str {
+        "SEND OK"
+    }
+
+    // the payload was accepted but the TCP layer couldn't push it out
+    fn send_fail(&self) -> &'static str {
+        "SEND FAIL"
+    }
+
+    // a command arriving while an earlier one is still running is dropped with one of these
+    fn busy_tokens(&self) -> &'static [&'static str] {
+        &["busy p...", "busy s..."]
+    }
+
+    // answer to sending on a link that is closed
+    fn link_invalid(&self) -> &'static str {
+        "link is not valid"
+    }
+}
+
+// Espressif's AT firmware for the ESP8266 and ESP32
+pub struct EspAt;
+
+impl Dialect for EspAt {}
@@ -0,0 +1,138 @@
+use core::fmt::Write;
+use core::str::{self, FromStr};
+use embedded_hal::digital::v2::OutputPin;
+use heapless::{String, Vec};
+
+use crate::{Error, ESP8266, MAX_STRING_SIZE};
+
+#[derive(Debug, Clone)]
+pub struct HttpHeaders {
+    pub status: u16,
+    pub content_length: Option<u32>,
+    pub content_type: String<64>,
+}
+
+#[derive(Debug, Clone)]
+pub struct HttpResponse<const N: usize> {
+    pub headers: HttpHeaders,
+    pub body: Vec<u8, N>,
+}
+
+impl FromStr for HttpHeaders {
+    type Err = Error;
+
+    fn from_str(input: &str) -> Result<Self, Self::Err> {
+        let mut lines = input.lines();
+        let status_line = lines.next().ok_or(Error::NoResponse)?;
+        if !status_line.starts_with("HTTP/") {
+            return Err(Error::UnexpectedResponse);
+        }
+        let status = status_line.split_whitespace().nth(1).and_then(|code| code.parse().ok()).ok_or(Error::Failure)?;
+        let mut headers = Self { status, content_length: None, content_type: String::new() };
+        for line in lines {
+            if let Some((name, value)) = line.split_once(':') {
+                let value = value.trim();
+                if name.trim().eq_ignore_ascii_case("Content-Length") {
+                    headers.content_length = value.parse().ok();
+                } else if name.trim().eq_ignore_ascii_case("Content-Type") {
+                    headers.content_type = String::from_str(value).unwrap_or_default();
+                }
+            }
+        }
+        Ok(headers)
+    }
+}
+
+impl<'a, USART, TIM, PIN, const FREQ: u32, const TRACE: usize> ESP8266<'a, USART, TIM, PIN, FREQ, TRACE>
+where
+    USART: stm32f1xx_hal::serial::Instance,
+    TIM: stm32f1xx_hal::timer::Instance,
+    PIN: OutputPin,
+{
+    // fetches only the response headers, e.g. to check Content-Length before a download
+    pub fn http_head(&mut self, host: &str, path: &str, port: u16) -> Result<HttpHeaders, Error> {
+        let response = self.http_request::<MAX_STRING_SIZE>("HEAD", host, path, port, &[], &[])?;
+        Ok(response.headers)
+    }
+
+    // `headers` are sent as given, e.g. [("Authorization", "Bearer ...")];
+    // N has to hold the response headers as well as the body
+    pub fn http_get<const N: usize>(&mut self, host: &str, path: &str, port: u16, headers: &[(&str, &str)]) -> Result<HttpResponse<N>, Error> {
+        self.http_request("GET", host, path, port, headers, &[])
+    }
+
+    pub fn http_post<const N: usize>(
+        &mut self,
+        host: &str,
+        path: &str,
+        port: u16,
+        headers: &[(&str, &str)],
+        body: &[u8],
+    ) -> Result<HttpResponse<N>, Error> {
+        self.http_request("POST", host, path, port, headers, body)
+    }
+
+    fn http_request<const N: usize>(
+        &mut self,
+        method: &str,
+        host: &str,
+        path: &str,
+        port: u16,
+        headers: &[(&str, &str)],
+        body: &[u8],
+    ) -> Result<HttpResponse<N>, Error> {
+        // a line break in either would end the request line or the Host header early
+        if path.contains(['\r', '\n']) || host.contains(['\r', '\n']) {
+            return Err(Error::InvalidParameter);
+        }
+        let mut request = String::<MAX_STRING_SIZE>::new();
+        write!(request, "{} {} HTTP/1.1\r\nHost: {}\r\nConnection: close\r\n", method, path, host)
+            .map_err(|_| Error::InvalidParameter)?;
+        for (name, value) in headers {
+            // a CR or LF would let the caller's data inject headers of its own
+            let bad_name = name.is_empty() || name.contains([':', '\r', '\n']);
+            if bad_name || value.contains(['\r', '\n']) {
+                return Err(Error::InvalidParameter);
+            }
+            write!(request, "{}: {}\r\n", name, value).map_err(|_| Error::InvalidParameter)?;
+        }
+        if !body.is_empty() {
+            write!(request, "Content-Length: {}\r\n", body.len()).map_err(|_| Error::InvalidParameter)?;
+        }
+        request.push_str("\r\n").map_err(|_| Error::InvalidParameter)?;
+        // the request's connection must not replace the server in the configuration
+        let config = self.config.clone();
+        let connected = self.connect_server("TCP", host, port);
+        self.config = config;
+        if !connected? {
+            return Err(Error::Failure);
+        }
+        let response = self.http_exchange(request.as_bytes(), body, method == "HEAD");
+        self.cip_close().ok();
+        response
+    }
+
+    fn http_exchange<const N: usize>(&mut self, head: &[u8], body: &[u8], head_only: bool) -> Result<HttpResponse<N>, Error> {
+        self.cip_send_bytes(head)?;
+        if !body.is_empty() {
+            self.cip_send_bytes(body)?;
+        }
+        let received = self.receiver().receive_until::<N>(b"\r\n\r\n", 5000_u32)?;
+        let split = received.windows(4).position(|window| window == b"\r\n\r\n").ok_or(Error::Failure)? + 4;
+        let headers: HttpHeaders = str::from_utf8(&received[..split]).map_err(|_| Error::Failure)?.parse()?;
+        let mut response = HttpResponse { headers, body: Vec::from_slice(&received[split..]).map_err(|_| Error::Failure)? };
+        if head_only {
+            return Ok(response);
+        }
+        // without a Content-Length the body runs until the server closes the connection
+        let wanted = response.headers.content_length.map_or(N, |len| len as usize);
+        if response.body.len() < wanted {
+            let missing = wanted.min(N) - response.body.len();
+            if let Ok(rest) = self.receiver().receive_at_least::<N>(missing, 5000_u32) {
+                let room = N - response.body.len();
+                response.body.extend_from_slice(&rest[..rest.len().min(room)]).ok();
+            }
+        }
+        Ok(response)
+    }
+}
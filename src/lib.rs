@@ -1,21 +1,300 @@
 #![no_std]
 
 use core::fmt::Write;
+use core::net::Ipv6Addr;
 use core::str::FromStr;
-use core::{cell::RefCell, str};
+use core::{
+    cell::{Ref, RefCell},
+    str,
+};
 use cortex_m_semihosting::hprintln;
-use heapless::{String, Vec};
+use embedded_hal::digital::v2::OutputPin;
+use heapless::{Deque, String, Vec};
 use stm32f1xx_hal::prelude::_fugit_ExtU32;
 use stm32f1xx_hal::{
-    gpio::{Output, PushPull, PA4},
-    serial::{Instance, Rx, Tx},
+    serial::{self, Rx, Tx},
     timer::Counter,
 };
 
+mod dialect;
+pub use dialect::{Dialect, EspAt};
+#[cfg(feature = "http")]
+mod http;
+#[cfg(feature = "http")]
+pub use http::{HttpHeaders, HttpResponse};
+#[cfg(feature = "sntp")]
+mod sntp;
+#[cfg(feature = "sntp")]
+pub use sntp::{DateTime, SntpConfig};
+
 #[derive(Debug)]
 pub enum Error {
     Failure,
     NoResponse,
+    InvalidParameter,
+    Timeout,
+    NotConnected,
+    UnexpectedResponse,
+    NotFound,
+    Unsupported,
+    // a response or payload didn't fit the buffer it was read into, the rest was drained and dropped
+    BufferOverflow,
+    // the USART overran while a reply was coming in, the data returned with it can't be trusted
+    Overrun,
+    // the echoed command differs from what was sent, it got corrupted on the way to the module
+    EchoMismatch,
+    // the firmware printed a crash banner and rebooted, carries the reset cause when it could be read
+    ModuleCrashed(Option<u8>),
+    // "busy p..." or "busy s...", the module is still on an earlier command and dropped this one
+    Busy,
+}
+
+#[derive(Debug)]
+pub struct SoftApConfig {
+    pub ssid: String<32>,
+    pub password: String<64>,
+    pub channel: u8,
+    pub encryption: u8,
+    pub max_conn: u8,
+    pub hidden: u8,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Esp8266Config {
+    pub mode: u8,
+    pub mux: bool,
+    // informational, the application sets up its USART at this rate before calling init, 0 if unknown
+    pub baud: u32,
+    pub ssid: String<32>,
+    pub password: String<64>,
+    pub protocol: String<4>,
+    pub host: String<64>,
+    // 0 skips connecting to a server
+    pub port: u16,
+    // overall budget in milliseconds for the whole init sequence
+    pub timeout: u32,
+}
+
+impl Default for Esp8266Config {
+    fn default() -> Self {
+        Self {
+            mode: 1,
+            mux: false,
+            baud: 0,
+            ssid: String::new(),
+            password: String::new(),
+            protocol: String::from_str("TCP").unwrap(),
+            host: String::new(),
+            port: 0,
+            timeout: 30000,
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub enum Event {
+    StationConnected(String<18>),
+    StationDisconnected(String<18>),
+    WifiDisconnected,
+    ConnectionClosed,
+    // mux mode, a client or outgoing link came up or went away
+    LinkConnected(u8),
+    LinkClosed(u8),
+}
+
+pub const CONFIG_BYTES: usize = 192;
+const CONFIG_VERSION: u8 = 1;
+
+impl Esp8266Config {
+    // layout: version, mode, mux, baud, port, timeout (little endian), the length prefixed ssid,
+    // password, protocol and host, then an FNV-1a checksum of everything before it.
+    // The password is stored in plain text since rejoining needs it back, keep the buffer in
+    // storage the application protects. Returns the number of bytes used
+    pub fn to_bytes(&self, buf: &mut [u8; CONFIG_BYTES]) -> usize {
+        let mut len = 0;
+        let mut put = |bytes: &[u8]| {
+            buf[len..len + bytes.len()].copy_from_slice(bytes);
+            len += bytes.len();
+        };
+        put(&[CONFIG_VERSION, self.mode, self.mux as u8]);
+        put(&self.baud.to_le_bytes());
+        put(&self.port.to_le_bytes());
+        put(&self.timeout.to_le_bytes());
+        for field in [self.ssid.as_str(), self.password.as_str(), self.protocol.as_str(), self.host.as_str()] {
+            put(&[field.len() as u8]);
+            put(field.as_bytes());
+        }
+        let checksum = fnv1a(&buf[..len]);
+        buf[len..len + 4].copy_from_slice(&checksum.to_le_bytes());
+        len + 4
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, Error> {
+        if bytes.len() < 4 {
+            return Err(Error::InvalidParameter);
+        }
+        let (payload, checksum) = bytes.split_at(bytes.len() - 4);
+        if fnv1a(payload).to_le_bytes() != checksum || payload.first() != Some(&CONFIG_VERSION) {
+            return Err(Error::InvalidParameter);
+        }
+        let mut rest = &payload[1..];
+        let mut take = |count: usize| -> Result<&[u8], Error> {
+            if rest.len() < count {
+                return Err(Error::InvalidParameter);
+            }
+            let (head, tail) = rest.split_at(count);
+            rest = tail;
+            Ok(head)
+        };
+        let mut config = Self::default();
+        let flags = take(2)?;
+        config.mode = flags[0];
+        config.mux = flags[1] != 0;
+        config.baud = u32::from_le_bytes(take(4)?.try_into().unwrap());
+        config.port = u16::from_le_bytes(take(2)?.try_into().unwrap());
+        config.timeout = u32::from_le_bytes(take(4)?.try_into().unwrap());
+        let mut field = || -> Result<&str, Error> {
+            let len = take(1)?[0] as usize;
+            str::from_utf8(take(len)?).map_err(|_| Error::InvalidParameter)
+        };
+        config.ssid = String::from_str(field()?).map_err(|_| Error::InvalidParameter)?;
+        config.password = String::from_str(field()?).map_err(|_| Error::InvalidParameter)?;
+        config.protocol = String::from_str(field()?).map_err(|_| Error::InvalidParameter)?;
+        config.host = String::from_str(field()?).map_err(|_| Error::InvalidParameter)?;
+        Ok(config)
+    }
+}
+
+// one +IPD frame, link_id is 0 when the module isn't in mux mode
+#[derive(Debug, Clone)]
+pub struct Frame<const N: usize> {
+    pub link_id: u8,
+    pub data: Vec<u8, N>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Encryption {
+    Open,            //0
+    Wep,             //1
+    WpaPsk,          //2
+    Wpa2Psk,         //3
+    WpaWpa2Psk,      //4
+    Wpa2Enterprise,  //5
+    Other(u8),
+}
+
+impl From<u8> for Encryption {
+    fn from(ecn: u8) -> Self {
+        match ecn {
+            0 => Encryption::Open,
+            1 => Encryption::Wep,
+            2 => Encryption::WpaPsk,
+            3 => Encryption::Wpa2Psk,
+            4 => Encryption::WpaWpa2Psk,
+            5 => Encryption::Wpa2Enterprise,
+            other => Encryption::Other(other),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct ApInfo {
+    pub encryption: Encryption,
+    pub ssid: String<32>,
+    pub rssi: i8,
+    pub bssid: String<18>,
+    pub channel: u8,
+}
+
+impl FromStr for ApInfo {
+    type Err = Error;
+
+    // the part inside +CWLAP:(...)
+    fn from_str(input: &str) -> Result<Self, Self::Err> {
+        let (ecn, rest) = next_field(input);
+        let (ssid, rest) = next_field(rest);
+        let (rssi, rest) = next_field(rest);
+        let (bssid, rest) = next_field(rest);
+        let (channel, _) = next_field(rest);
+        Ok(Self {
+            encryption: Encryption::from(ecn.parse::<u8>().map_err(|_| Error::Failure)?),
+            ssid: String::from_str(ssid).map_err(|_| Error::Failure)?,
+            rssi: rssi.parse().map_err(|_| Error::Failure)?,
+            bssid: String::from_str(bssid).map_err(|_| Error::Failure)?,
+            channel: channel.parse().map_err(|_| Error::Failure)?,
+        })
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ModuleMode {
+    Command,
+    // was passing data through, the probe escaped it back to command mode
+    Transparent,
+}
+
+// the self_test stages, in the order they run
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SelfTestStage {
+    At,
+    Mode,
+    Wifi,
+    Dns,
+    Tcp,
+}
+
+#[derive(Debug)]
+pub struct SelfTestReport {
+    // the first stage that failed, the ones after it didn't run
+    pub failed: Option<SelfTestStage>,
+    // why it failed, None when the module answered but with the wrong state
+    pub error: Option<Error>,
+}
+
+impl SelfTestReport {
+    pub fn passed(&self) -> bool {
+        self.failed.is_none()
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct IpInfo {
+    pub ip: String<16>,
+    pub mac: String<18>,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct LinkQuality {
+    pub rssi: i8,
+    pub channel: u8,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct DhcpLease {
+    // the raw +CWDHCP bitmask, which bit is the station moved between firmware versions
+    pub state: u8,
+    pub ip: String<16>,
+    pub gateway: String<16>,
+    pub netmask: String<16>,
+}
+
+#[derive(Debug, Clone, Copy, Default)]
+pub struct PingStats {
+    pub sent: u8,
+    pub received: u8,
+    // round trip times in milliseconds, all 0 when nothing came back
+    pub min: u32,
+    pub avg: u32,
+    pub max: u32,
+}
+
+impl PingStats {
+    pub fn loss_percent(&self) -> u8 {
+        if self.sent == 0 {
+            return 0;
+        }
+        (100 - self.received as u32 * 100 / self.sent as u32) as u8
+    }
 }
 
 #[derive(Debug)]
@@ -28,130 +307,987 @@ pub enum CipStatus {
     WifiConnectFailed,  //5
 }
 
-const ESP_RX_BUFF_SIZE: usize = 256;
 const ESP_TX_BUFF_SIZE: usize = 256;
 
 pub const MAX_STRING_SIZE: usize = 1024;
 const RECEIVE_PIECE_LEN: usize = 8;
+const EVENT_QUEUE_LEN: usize = 8;
+const PENDING_LEN: usize = 64;
+pub const LINE_BUFF_SIZE: usize = 128;
+const MAX_LINK_ID: u8 = 4;
+pub const COALESCE_BUFF_SIZE: usize = 256;
+const FLUSH_QUIET_MS: u32 = 5;
+const BAUD_STEPS: [u32; 8] = [921600, 460800, 230400, 115200, 57600, 38400, 19200, 9600];
+const BAUD_TUNE_PROBES: usize = 4;
+const BAUD_TUNE_THRESHOLD: u32 = 2;
+const BAUD_TUNE_MAX_FAILURES: usize = 1;
+// one CIPSEND is capped at 2048 bytes by the firmware, the chunk lives on the stack
+pub const SEND_CHUNK_MAX: usize = 512;
+pub const SELF_TEST_HOST: &str = "www.espressif.com";
+pub const SELF_TEST_PORT: u16 = 80;
+
+// ring buffer over the last N bytes sent and received
+struct Trace<const N: usize> {
+    buf: [u8; N],
+    start: usize,
+    len: usize,
+}
+
+impl<const N: usize> Trace<N> {
+    fn new() -> Self {
+        Self { buf: [0; N], start: 0, len: 0 }
+    }
+
+    // called for every received byte, so constant time per byte
+    fn record(&mut self, bytes: &[u8]) {
+        if N == 0 {
+            return;
+        }
+        for byte in bytes {
+            self.buf[(self.start + self.len) % N] = *byte;
+            if self.len < N {
+                self.len += 1;
+            } else {
+                self.start = (self.start + 1) % N;
+            }
+        }
+    }
+
+    // oldest bytes first, the second slice continues where the first ends
+    fn as_slices(&self) -> (&[u8], &[u8]) {
+        let end = self.start + self.len;
+        if end <= N {
+            (&self.buf[self.start..end], &[])
+        } else {
+            (&self.buf[self.start..], &self.buf[..end - N])
+        }
+    }
+}
+
+// small payloads waiting to go out together, see set_coalesce
+struct Coalesce {
+    window: u32,
+    max_bytes: usize,
+    staged: Vec<u8, COALESCE_BUFF_SIZE>,
+    // the timer got used for something else while staging, the window counts as over
+    window_lost: bool,
+}
+
+// shared by both halves like the timer, the Sender records what the Receiver looks for
+struct Echo {
+    // what the module is believed to do, the command is only looked for while echo is on
+    enabled: bool,
+    // the last command while echo is on, the first line of its response when it echoes back
+    sent: String<ESP_TX_BUFF_SIZE>,
+}
+
+// receive side state, borrowed by the Receiver half
+struct RxState {
+    strip_echo: bool,
+    events: Deque<Event, EVENT_QUEUE_LEN>,
+    // bytes lost to overrun, framing/noise/parity errors or a full buffer
+    dropped: u32,
+    trim: bool,
+    // set while a requested restart is in flight so its boot banner isn't taken for a crash
+    expect_reboot: bool,
+    // latched until maintain_connection recovered, the matching events may long be consumed
+    wifi_lost: bool,
+    link_lost: bool,
+    verify_echo: bool,
+    // bytes picked up while a write was in progress, handed out before anything new
+    pending: Deque<u8, PENDING_LEN>,
+    overrun: bool,
+    // a CIPSEND payload went out and neither SEND OK nor SEND FAIL was seen for it yet, settled
+    // by whichever read comes across the answer
+    send_pending: bool,
+    // run while a receive loop has nothing to read, None spins
+    idle: Option<fn()>,
+    dialect: &'static dyn Dialect,
+}
+
+impl RxState {
+    fn idle(&self) {
+        if let Some(idle) = self.idle {
+            idle();
+        }
+    }
+
+    // picks unsolicited notifications out of a raw response, the oldest event is dropped when full
+    fn capture_events(&mut self, response: &str) {
+        for line in response.lines() {
+            let status = line.trim_end();
+            if [self.dialect.send_ok(), self.dialect.send_fail(), self.dialect.link_invalid()].contains(&status) {
+                self.send_pending = false;
+                continue;
+            }
+            let event = if let Some(mac) = line.strip_prefix("+STA_CONNECTED:") {
+                Event::StationConnected(String::from_str(next_field(mac).0).unwrap_or_default())
+            } else if let Some(mac) = line.strip_prefix("+STA_DISCONNECTED:") {
+                Event::StationDisconnected(String::from_str(next_field(mac).0).unwrap_or_default())
+            } else if line.trim_end() == "WIFI DISCONNECT" {
+                self.wifi_lost = true;
+                Event::WifiDisconnected
+            } else if line.trim_end() == "CLOSED" {
+                self.link_lost = true;
+                Event::ConnectionClosed
+            } else if let Some((link_id, notice)) = line.trim_end().split_once(',') {
+                match (link_id.parse(), notice) {
+                    (Ok(link_id), "CONNECT") => Event::LinkConnected(link_id),
+                    (Ok(link_id), "CLOSED") => Event::LinkClosed(link_id),
+                    _ => continue,
+                }
+            } else {
+                continue;
+            };
+            if self.events.is_full() {
+                self.events.pop_front();
+            }
+            self.events.push_back(event).ok();
+        }
+    }
+}
+
+// incremental parser for +IPD,[<id>,]<len>:<data> frames, fed one byte at a time
+struct IpdParser {
+    prefix: &'static str,
+    header: String<32>,
+    remaining: usize,
+    link_id: Option<u8>,
+    // text between frames, so notifications arriving there still reach capture_events
+    line: String<LINE_BUFF_SIZE>,
+    line_done: bool,
+    overlong: bool,
+}
+
+impl IpdParser {
+    fn new(prefix: &'static str) -> Self {
+        Self {
+            prefix,
+            header: String::new(),
+            remaining: 0,
+            link_id: None,
+            line: String::new(),
+            line_done: false,
+            overlong: false,
+        }
+    }
+
+    // returns the byte back when it belongs to a frame payload
+    fn feed(&mut self, byte: u8) -> Option<u8> {
+        if self.remaining > 0 {
+            self.remaining -= 1;
+            return Some(byte);
+        }
+        if self.line_done {
+            self.line.clear();
+            self.line_done = false;
+            self.overlong = false;
+        }
+        if byte == b'\n' {
+            self.line_done = true;
+        } else if self.line.push(byte as char).is_err() {
+            self.overlong = true;
+        }
+        if self.prefix.as_bytes().first() == Some(&byte) {
+            self.header.clear();
+        }
+        if byte == b'\n' || self.header.push(byte as char).is_err() {
+            self.header.clear();
+            return None;
+        }
+        if byte == b':' {
+            if let Some(fields) = self.header.strip_prefix(self.prefix).and_then(|h| h.strip_suffix(':')) {
+                let (link_id, len) = match fields.rfind(',') {
+                    Some(pos) => (fields[..pos].parse().ok(), &fields[pos + 1..]),
+                    None => (None, fields),
+                };
+                if let Ok(len) = len.parse() {
+                    self.remaining = len;
+                    self.link_id = link_id;
+                    // the header isn't a line of its own
+                    self.line.clear();
+                    self.overlong = false;
+                }
+            }
+            self.header.clear();
+        }
+        None
+    }
+
+    // the out-of-frame line the last byte completed, lines longer than LINE_BUFF_SIZE are skipped
+    fn line(&self) -> Option<&str> {
+        if self.line_done && !self.overlong {
+            Some(self.line.trim_end())
+        } else {
+            None
+        }
+    }
+
+    fn in_frame(&self) -> bool {
+        self.remaining > 0
+    }
+}
 
 // ESP8266 结构体定义
-pub struct ESP8266<'a, USART, TIM, const FREQ: u32> {
+pub struct ESP8266<'a, USART, TIM, PIN, const FREQ: u32, const TRACE: usize = 0> {
     tx: Tx<USART>,
     rx: Rx<USART>,
-    power_pin: PA4<Output<PushPull>>,
-    timer: &'a mut Counter<TIM, FREQ>,
+    power_pin: PIN,
+    power_active_low: bool,
+    timer: RefCell<&'a mut Counter<TIM, FREQ>>,
+    trace: RefCell<Trace<TRACE>>,
+    echo: RefCell<Echo>,
+    budget: Option<u32>,
+    config: Esp8266Config,
+    ipv6: bool,
+    // major AT firmware version, read once by at_version_major
+    at_major: Option<u8>,
+    auto_flush: bool,
+    coalesce: Coalesce,
+    // maintain_connection ticks to skip before the next attempt, doubled on every failure
+    reconnect_backoff: u16,
+    reconnect_failures: u8,
+    rx_state: RxState,
+}
+
+impl<'a, USART, TIM, PIN, const FREQ: u32> ESP8266<'a, USART, TIM, PIN, FREQ>
+where
+    USART: stm32f1xx_hal::serial::Instance,
+    TIM: stm32f1xx_hal::timer::Instance,
+    PIN: OutputPin,
+{
+    // `power_pin` drives the module's enable line, high means on unless set_power_active_low
+    pub fn new(tx: Tx<USART>, rx: Rx<USART>, power_pin: PIN, timer: &'a mut Counter<TIM, FREQ>) -> Self {
+        Self::with_trace(tx, rx, power_pin, timer)
+    }
 }
 
-impl<'a, USART, TIM, const FREQ: u32> ESP8266<'a, USART, TIM, FREQ>
+impl<'a, USART, TIM, PIN, const FREQ: u32, const TRACE: usize> ESP8266<'a, USART, TIM, PIN, FREQ, TRACE>
 where
     USART: stm32f1xx_hal::serial::Instance,
     TIM: stm32f1xx_hal::timer::Instance,
+    PIN: OutputPin,
 {
-    pub fn new(mut tx: Tx<USART>, mut rx: Rx<USART>, power_pin: PA4<Output<PushPull>>, timer: &'a mut Counter<TIM, FREQ>) -> Self {
+    // like new, additionally recording the last TRACE bytes of traffic for trace_dump
+    pub fn with_trace(tx: Tx<USART>, mut rx: Rx<USART>, power_pin: PIN, timer: &'a mut Counter<TIM, FREQ>) -> Self {
         rx.listen();
-        Self { tx, rx, power_pin, timer }
+        Self {
+            tx,
+            rx,
+            power_pin,
+            power_active_low: false,
+            timer: RefCell::new(timer),
+            trace: RefCell::new(Trace::new()),
+            echo: RefCell::new(Echo { enabled: true, sent: String::new() }),
+            budget: None,
+            config: Esp8266Config::default(),
+            ipv6: false,
+            at_major: None,
+            auto_flush: false,
+            coalesce: Coalesce { window: 0, max_bytes: 0, staged: Vec::new(), window_lost: false },
+            reconnect_backoff: 0,
+            reconnect_failures: 0,
+            rx_state: RxState {
+                strip_echo: true,
+                events: Deque::new(),
+                dropped: 0,
+                trim: true,
+                expect_reboot: false,
+                wifi_lost: false,
+                link_lost: false,
+                verify_echo: false,
+                pending: Deque::new(),
+                overrun: false,
+                send_pending: false,
+                idle: None,
+                dialect: &EspAt,
+            },
+        }
     }
 
     pub fn power_on(&mut self) {
-        self.power_pin.set_high();
+        self.set_power(true);
     }
 
     pub fn power_off(&mut self) {
-        self.power_pin.set_low();
+        self.set_power(false);
+    }
+
+    // for boards that switch the enable line through an inverting stage
+    pub fn set_power_active_low(&mut self, active_low: bool) {
+        self.power_active_low = active_low;
+    }
+
+    fn set_power(&mut self, on: bool) {
+        if on != self.power_active_low {
+            self.power_pin.set_high().ok();
+        } else {
+            self.power_pin.set_low().ok();
+        }
+    }
+
+    // with trimming on (the default) leading and trailing whitespace, including the final \r\n,
+    // is cut from every response returned by recv_string_with_flag and friends after the echo
+    // line is dropped; off hands back the received bytes untouched. The driver's own status checks
+    // (restart, connect_server, test, set_mode, ...) only look at the status line so they work either way
+    pub fn set_trim(&mut self, enable: bool) {
+        self.rx_state.trim = enable;
+    }
+
+    // called whenever a receive loop finds no byte waiting, e.g. with cortex_m::asm::wfi to sleep
+    // until the next interrupt. Something has to wake it in time: RXNE (set_rx_interrupt) for the
+    // incoming bytes and the driver timer's update interrupt for the timeouts. None, the default,
+    // polls in a tight loop
+    pub fn set_idle_hook(&mut self, hook: Option<fn()>) {
+        self.rx_state.idle = hook;
+    }
+
+    // the driver itself only polls, so the RXNE interrupt is just needed when the application
+    // has its own USART handler; disable it if nothing services that interrupt
+    pub fn set_rx_interrupt(&mut self, enable: bool) {
+        if enable {
+            self.rx.listen();
+        } else {
+            self.rx.unlisten();
+        }
+    }
+
+    // ATE1/ATE0, on at power up and again after every reset; only while on is the echoed command looked for and dropped
+    pub fn set_echo(&mut self, enable: bool) -> Result<bool, Error> {
+        self.send(if enable { "ATE1" } else { "ATE0" });
+        let receive = self.recv_string_with_flag(self.ok(), 3000_u32)?;
+        if receive.ends_with(self.ok()) {
+            self.echo.get_mut().enabled = enable;
+        }
+        Ok(receive.ends_with(self.ok()))
+    }
+
+    // by default a first response line repeating the command is treated as its echo and dropped,
+    // turn this off to keep it; the driver's own checks look past it either way
+    pub fn set_strip_echo(&mut self, enable: bool) {
+        self.rx_state.strip_echo = enable;
+    }
+
+    // needs echo on (the default), a response whose first line isn't the command byte for byte
+    // fails with EchoMismatch
+    pub fn set_verify_echo(&mut self, enable: bool) {
+        self.rx_state.verify_echo = enable;
+    }
+
+    // EspAt unless set, for firmware that frames its responses differently
+    pub fn set_dialect(&mut self, dialect: &'static dyn Dialect) {
+        self.rx_state.dialect = dialect;
+    }
+
+    fn ok(&self) -> &'static str {
+        self.rx_state.dialect.success_terminator()
+    }
+
+    fn prompt(&self) -> &'static str {
+        self.rx_state.dialect.prompt()
+    }
+
+    // waits for unsolicited traffic so notifications get queued while the application is idle
+    pub fn poll_events(&mut self, timeout: u32) {
+        self.recv_string_with_timeout(timeout).ok();
+    }
+
+    pub fn next_event(&mut self) -> Option<Event> {
+        self.rx_state.events.pop_front()
+    }
+
+    // the recorded conversation, TX and RX interleaved as they happened, as the two halves of the
+    // ring: oldest bytes first, the second continues the first. They come as Ref guards rather than
+    // plain slices since the buffer lives in a RefCell shared with the split halves
+    pub fn trace_dump(&self) -> (Ref<'_, [u8]>, Ref<'_, [u8]>) {
+        Ref::map_split(self.trace.borrow(), |trace| trace.as_slices())
+    }
+
+    pub fn clear_trace(&mut self) {
+        self.trace.get_mut().len = 0;
+    }
+
+    pub fn dropped_byte_count(&self) -> u32 {
+        self.rx_state.dropped
+    }
+
+    pub fn reset_dropped_count(&mut self) {
+        self.rx_state.dropped = 0;
     }
 
     pub fn send(&mut self, command: &str) -> bool {
-        let mut base: String<4096> = String::new();
-        base.push_str(command).unwrap();
-        base.push_str("\r\n").unwrap();
-        let command = base.as_str();
-        self.tx.write_str(command).unwrap();
-        true
+        if self.auto_flush {
+            self.flush_rx();
+        }
+        self.sender().send(command)
+    }
+
+    // discards pending input, unsolicited notifications in it still reach the event queue
+    pub fn flush_rx(&mut self) {
+        self.receiver().flush();
+    }
+
+    // off by default; when on every command starts from a drained receive path so a stale
+    // notification can't be mistaken for its response
+    pub fn set_auto_flush_before_send(&mut self, enable: bool) {
+        self.auto_flush = enable;
+    }
+
+    pub fn send_escape(&mut self) {
+        self.sender().send_escape()
+    }
+
+    // splits the driver into independent transmit and receive halves for full-duplex use,
+    // both halves share the timer
+    pub fn split(&mut self) -> (Sender<'_, 'a, USART, TIM, FREQ, TRACE>, Receiver<'_, 'a, USART, TIM, FREQ, TRACE>) {
+        // whatever runs on the halves restarts the timer the coalescing window was counted on
+        self.coalesce.window_lost |= !self.coalesce.staged.is_empty();
+        let sender = Sender { tx: &mut self.tx, timer: &self.timer, trace: &self.trace, echo: &self.echo };
+        let receiver = Receiver {
+            rx: &mut self.rx,
+            timer: &self.timer,
+            trace: &self.trace,
+            echo: &self.echo,
+            state: &mut self.rx_state,
+        };
+        (sender, receiver)
+    }
+
+    fn pause(&mut self, millis: u32) {
+        self.sender().pause(millis)
+    }
+
+    fn sender(&mut self) -> Sender<'_, 'a, USART, TIM, FREQ, TRACE> {
+        self.split().0
+    }
+
+    fn receiver(&mut self) -> Receiver<'_, 'a, USART, TIM, FREQ, TRACE> {
+        self.split().1
     }
 
     pub fn test(&mut self) -> Result<bool, Error> {
         self.send("AT");
-        let receive = self.recv_string("OK")?;
-        Ok(receive == "OK")
+        let receive = self.recv_string(self.ok())?;
+        Ok(status_line(&receive) == self.ok())
+    }
+
+    // in transparent mode the probing AT ends up at the server as 4 bytes of data
+    pub fn mode_probe(&mut self) -> Result<ModuleMode, Error> {
+        if let Ok(true) = self.test() {
+            return Ok(ModuleMode::Command);
+        }
+        self.send_escape();
+        // the first command after +++ may still see leftovers of the escape
+        for _ in 0..2 {
+            if let Ok(true) = self.test() {
+                return Ok(ModuleMode::Transparent);
+            }
+        }
+        Err(Error::NoResponse)
+    }
+
+    // backs out of a CIPSEND after its > prompt without sending the announced bytes: a +++ on its
+    // own aborts the send on ESP-AT, the module drops what it got so far and answers AT again
+    pub fn cancel_send(&mut self) -> Result<(), Error> {
+        self.flush_rx();
+        self.send_escape();
+        self.rx_state.send_pending = false;
+        // the abort may be reported as SEND FAIL or ERROR before the module listens again
+        self.flush_rx();
+        for _ in 0..2 {
+            if let Ok(true) = self.test() {
+                return Ok(());
+            }
+        }
+        Err(Error::NoResponse)
+    }
+
+    // discards everything until a plain OK answers an AT again, lighter than a restart after a
+    // read left junk in the pipe
+    pub fn resync(&mut self, timeout: u32) -> Result<(), Error> {
+        let outer = self.budget.replace(timeout);
+        let result = loop {
+            // drop whatever is still in flight before asking
+            if let Err(Error::Timeout) = self.recv_string_with_timeout(50_u32) {
+                break Err(Error::Timeout);
+            }
+            self.send("AT");
+            match self.recv_string_with_flag(self.ok(), 500_u32) {
+                Ok(receive) if receive.lines().last().map(str::trim) == Some(self.ok()) => break Ok(()),
+                Err(Error::Timeout) => break Err(Error::Timeout),
+                _ => {}
+            }
+        };
+        let used = timeout - self.budget.unwrap_or(0);
+        self.budget = outer.map(|budget| budget.saturating_sub(used));
+        result
     }
 
     pub fn restart(&mut self) -> Result<bool, Error> {
         self.send("AT+RST");
-        let receive = self.recv_string_with_flag("ready", 5000_u32)?;
-        let receive = Self::remove_first_line(receive.trim_end());
+        self.rx_state.expect_reboot = true;
+        let receive = self.recv_string_with_flag(self.rx_state.dialect.ready(), 5000_u32);
+        self.rx_state.expect_reboot = false;
+        let receive = receive?;
+        // the boot banner came, the module is back to its power up defaults
+        self.echo.get_mut().enabled = true;
+        self.ipv6 = false;
         hprintln!("restart receive:{}", receive);
-        Ok(&receive[..2] == "OK")
+        Ok(receive.lines().any(|line| line.trim() == self.ok()))
+    }
+
+    pub fn init(&mut self, config: &Esp8266Config) -> Result<(), Error> {
+        self.budget = Some(config.timeout);
+        let result = self.init_steps(config);
+        self.budget = None;
+        result
+    }
+
+    // the configuration as last applied through this driver, to be stored and handed back to init
+    pub fn config(&self) -> &Esp8266Config {
+        &self.config
+    }
+
+    fn init_steps(&mut self, config: &Esp8266Config) -> Result<(), Error> {
+        if !self.test()? || !self.restart()? || !self.set_mode(config.mode)? {
+            return Err(Error::Failure);
+        }
+        if !self.set_mux(config.mux)? {
+            return Err(Error::Failure);
+        }
+        self.config.baud = config.baud;
+        self.config.timeout = config.timeout;
+        if !config.ssid.is_empty() && !self.join_ap(&config.ssid, &config.password)? {
+            return Err(Error::Failure);
+        }
+        if config.port != 0 && !self.connect_server(&config.protocol, &config.host, config.port)? {
+            return Err(Error::Failure);
+        }
+        Ok(())
+    }
+
+    // AT answers, the mode is the configured one, the station is associated, SELF_TEST_HOST resolves
+    // and takes a TCP connection on SELF_TEST_PORT. An already open connection counts as the
+    // last two and is left alone, otherwise the test connection is closed again
+    pub fn self_test(&mut self) -> Result<SelfTestReport, Error> {
+        let mut stage = SelfTestStage::At;
+        let result = self.self_test_stages(&mut stage);
+        Ok(match result {
+            Ok(true) => SelfTestReport { failed: None, error: None },
+            Ok(false) => SelfTestReport { failed: Some(stage), error: None },
+            Err(error) => SelfTestReport { failed: Some(stage), error: Some(error) },
+        })
+    }
+
+    fn self_test_stages(&mut self, stage: &mut SelfTestStage) -> Result<bool, Error> {
+        if !self.test()? {
+            return Ok(false);
+        }
+        *stage = SelfTestStage::Mode;
+        if self.get_mode()? != self.config.mode {
+            return Ok(false);
+        }
+        *stage = SelfTestStage::Wifi;
+        match self.cip_status()? {
+            CipStatus::ServerConnected => return Ok(true),
+            CipStatus::WifiConnected | CipStatus::ServerDisconnected => {}
+            _ => return Ok(false),
+        }
+        *stage = SelfTestStage::Dns;
+        let ip = self.resolve(SELF_TEST_HOST)?;
+        *stage = SelfTestStage::Tcp;
+        // the test connection must not end up in the configuration handed back by config()
+        let config = self.config.clone();
+        let connected = self.connect_server("TCP", &ip, SELF_TEST_PORT);
+        self.config = config;
+        if connected? {
+            self.cip_close()?;
+            return Ok(true);
+        }
+        Ok(false)
+    }
+
+    pub fn get_mode(&mut self) -> Result<u8, Error> {
+        let receive = self.expect_prefix("AT+CWMODE?", "+CWMODE:", 3000_u32)?;
+        for line in receive.lines() {
+            if let Some(mode) = line.strip_prefix("+CWMODE:") {
+                return mode.trim().parse().map_err(|_| Error::UnexpectedResponse);
+            }
+        }
+        Err(Error::NoResponse)
+    }
+
+    // the address the module's DNS client gets for `host`
+    pub fn resolve(&mut self, host: &str) -> Result<String<40>, Error> {
+        let mut command = String::<ESP_TX_BUFF_SIZE>::new();
+        write!(command, "AT+CIPDOMAIN=\"{}\"", host).map_err(|_| Error::InvalidParameter)?;
+        // an unknown host answers with DNS Fail and ERROR
+        self.send(&command);
+        let receive = match self.recv_string_with_flag(self.ok(), 10000_u32) {
+            Err(Error::Failure) => return Err(Error::NotFound),
+            receive => receive?,
+        };
+        for line in receive.lines() {
+            if let Some(ip) = line.strip_prefix("+CIPDOMAIN:") {
+                return String::from_str(next_field(ip).0).map_err(|_| Error::UnexpectedResponse);
+            }
+        }
+        Err(Error::NoResponse)
     }
 
     pub fn set_mode(&mut self, mode: u8) -> Result<bool, Error> {
         let mut command = String::<ESP_TX_BUFF_SIZE>::new();
         write!(command, "AT+CWMODE={}", mode).ok();
         self.send(&command);
-        let receive = self.recv_string_with_flag("OK", 3000_u32)?;
+        let receive = self.recv_string_with_flag(self.ok(), 3000_u32)?;
         hprintln!("set_mode receive:{}", receive);
-        Ok(receive == "OK")
+        if status_line(&receive) == self.ok() {
+            self.config.mode = mode;
+        }
+        Ok(status_line(&receive) == self.ok())
     }
 
     pub fn set_cip_mode(&mut self, mode: u8) -> Result<bool, Error> {
         let mut command = String::<ESP_TX_BUFF_SIZE>::new();
         write!(command, "AT+CIPMODE={}", mode).ok();
         self.send(&command);
-        let receive = self.recv_string_with_flag("OK", 3000_u32)?;
+        let receive = self.recv_string_with_flag(self.ok(), 3000_u32)?;
         hprintln!("set_mode receive:{}", receive);
-        Ok(receive == "OK")
+        Ok(status_line(&receive) == self.ok())
+    }
+
+    // ESP-AT 2.x only, older firmware answers ERROR; a reset turns it off again
+    pub fn enable_ipv6(&mut self, enable: bool) -> Result<bool, Error> {
+        let mut command = String::<ESP_TX_BUFF_SIZE>::new();
+        write!(command, "AT+CIPV6={}", enable as u8).ok();
+        self.send(&command);
+        match self.recv_string_with_flag(self.ok(), 3000_u32) {
+            Ok(receive) => {
+                self.ipv6 = enable && receive.ends_with(self.ok());
+                Ok(receive.ends_with(self.ok()))
+            }
+            Err(Error::Failure) => Err(Error::Unsupported),
+            Err(error) => Err(error),
+        }
     }
 
+    // `ip` is a host name, an IPv4 address or a bracketed IPv6 literal like "[2001:db8::1]",
+    // the latter needs enable_ipv6 first
     pub fn connect_server(&mut self, mode: &str, ip: &str, port: u16) -> Result<bool, Error> {
         let mut command = String::<ESP_TX_BUFF_SIZE>::new();
-        write!(command, "AT+CIPSTART=\"{}\",\"{}\",{}", mode, ip, port).ok();
+        if let Some(ipv6) = ip.strip_prefix('[').and_then(|ip| ip.strip_suffix(']')) {
+            if Ipv6Addr::from_str(ipv6).is_err() {
+                return Err(Error::InvalidParameter);
+            }
+            if !self.ipv6 {
+                return Err(Error::Unsupported);
+            }
+            let suffix = if mode.ends_with("v6") { "" } else { "v6" };
+            write!(command, "AT+CIPSTART=\"{}{}\",\"{}\",{}", mode, suffix, ipv6, port).ok();
+        } else if ip.contains(':') {
+            return Err(Error::InvalidParameter);
+        } else {
+            write!(command, "AT+CIPSTART=\"{}\",\"{}\",{}", mode, ip, port).ok();
+        }
         self.send(&command);
-        let receive = self.recv_string_with_flag("OK", 5000_u32)?;
-        let receive = Self::remove_first_line(receive.trim_end());
-        Ok(receive.ends_with("OK"))
+        let receive = self.recv_string_with_flag(self.ok(), 5000_u32)?;
+        let receive = receive.trim_end();
+        if receive.ends_with(self.ok()) {
+            self.config.protocol = String::from_str(mode).unwrap_or_default();
+            self.config.host = String::from_str(ip).unwrap_or_default();
+            self.config.port = port;
+        }
+        Ok(receive.ends_with(self.ok()))
     }
 
     pub fn cip_send(&mut self, data: &str) -> Result<String<MAX_STRING_SIZE>, Error> {
+        self.cip_send_on(None, data)
+    }
+
+    // mux mode, sends on link 0-4 and returns what that link answered
+    pub fn cip_send_mux(&mut self, link_id: u8, data: &str) -> Result<String<MAX_STRING_SIZE>, Error> {
+        if link_id > MAX_LINK_ID {
+            return Err(Error::InvalidParameter);
+        }
+        self.cip_send_on(Some(link_id), data)
+    }
+
+    fn cip_send_on(&mut self, link_id: Option<u8>, data: &str) -> Result<String<MAX_STRING_SIZE>, Error> {
         let len = data.len() + 2;
         let mut command = String::<ESP_TX_BUFF_SIZE>::new();
-        write!(command, "AT+CIPSEND={}", len).ok();
+        match link_id {
+            Some(link_id) => write!(command, "AT+CIPSEND={},{}", link_id, len).ok(),
+            None => write!(command, "AT+CIPSEND={}", len).ok(),
+        };
         self.send(&command);
-        let receive = self.recv_string_with_flag(">", 3000_u32)?;
-        if !receive.ends_with(">") {
+        let receive = self.recv_string_with_flag(self.prompt(), 3000_u32)?;
+        if !receive.ends_with(self.prompt()) {
             return Err(Error::Failure);
         }
         // self.timer.start(1000.millis()).unwrap();
         // self.timer.wait().unwrap_or_default();
-        self.send(data);
+        self.rx_state.overrun = false;
+        self.rx_state.send_pending = true;
+        self.write_reading(data.as_bytes());
+        self.write_reading(b"\r\n");
+        // a timeout leaves send_pending set, the data may still be on its way
         let receive = self.recv_string_with_timeout(2000_u32)?;
-        for line in receive.lines() {
-            if line.starts_with("+IPD") {
-                if let Some(pos) = line.find(':') {
-                    let data_str = &line[pos + 1..line.len()];
+        // anything before SEND OK belongs to an earlier exchange
+        let (_, reply) = receive.split_once(self.rx_state.dialect.send_ok()).ok_or(Error::NoResponse)?;
+        if self.rx_state.overrun {
+            return Err(Error::Overrun);
+        }
+        for line in reply.lines() {
+            if let Some((from, data_str)) = parse_ipd_line(line, self.rx_state.dialect.ipd_format()) {
+                if link_id.is_none() || from == link_id {
                     hprintln!("receive:{}", data_str);
-                    return Ok(String::from_str(data_str).unwrap());
+                    return String::from_str(data_str).map_err(|_| Error::BufferOverflow);
                 }
             }
         }
         Err(Error::NoResponse)
     }
 
-    pub fn cip_receive(&mut self) -> Result<String<MAX_STRING_SIZE>, Error> {
-        let receive = self.recv_string_with_timeout(1000_u32)?;
-        for line in receive.lines() {
-            if line.starts_with("+IPD") {
-                if let Some(pos) = line.find(':') {
-                    let data_str = &line[pos + 1..line.len()];
-                    hprintln!("receive:{}", data_str);
-                    return Ok(String::from_str(data_str).unwrap());
-                }
+    // a long payload takes longer to write than the module needs to start answering, so the
+    // receive side is drained between bytes instead of overrunning until the write is done
+    fn write_reading(&mut self, data: &[u8]) {
+        self.trace.get_mut().record(data);
+        let (sender, mut receiver) = self.split();
+        for &byte in data {
+            while sender.tx.write(byte).is_err() {
+                receiver.stash();
             }
         }
-        Err(Error::NoResponse)
+        receiver.stash();
+    }
+
+    // sends raw bytes on the current link and waits for the module to confirm them
+    pub fn cip_send_bytes(&mut self, data: &[u8]) -> Result<(), Error> {
+        let mut command = String::<ESP_TX_BUFF_SIZE>::new();
+        write!(command, "AT+CIPSEND={}", data.len()).ok();
+        self.send(&command);
+        let receive = self.recv_string_with_flag(self.prompt(), 3000_u32)?;
+        if !receive.ends_with(self.prompt()) {
+            return Err(Error::Failure);
+        }
+        self.rx_state.overrun = false;
+        self.rx_state.send_pending = true;
+        self.write_reading(data);
+        self.recv_string_with_flag(self.rx_state.dialect.send_ok(), 3000_u32)?;
+        if self.rx_state.overrun {
+            return Err(Error::Overrun);
+        }
+        Ok(())
+    }
+
+    // sends total_len bytes pulled from `producer` in chunks of up to chunk_size (at most
+    // SEND_CHUNK_MAX), each one confirmed before the next is asked for. The producer fills the
+    // slice and returns how much it wrote, 0 before the end aborts the upload
+    pub fn cip_send_stream(&mut self, total_len: usize, chunk_size: usize, mut producer: impl FnMut(&mut [u8]) -> usize) -> Result<(), Error> {
+        if chunk_size == 0 || chunk_size > SEND_CHUNK_MAX {
+            return Err(Error::InvalidParameter);
+        }
+        let mut chunk = [0u8; SEND_CHUNK_MAX];
+        let mut sent = 0;
+        while sent < total_len {
+            let wanted = chunk_size.min(total_len - sent);
+            let len = producer(&mut chunk[..wanted]).min(wanted);
+            if len == 0 {
+                return Err(Error::Failure);
+            }
+            self.cip_send_bytes(&chunk[..len])?;
+            sent += len;
+        }
+        Ok(())
+    }
+
+    // off (window 0) by default. When on, send_coalesced stages payloads up to max_bytes (at most
+    // COALESCE_BUFF_SIZE) and sends them as one CIPSEND once full, once window_ms passed since the
+    // first one was staged, or on flush. The window runs on the driver's timer, any other driver
+    // call in between ends it early and the next send_coalesced or poll_coalesce sends
+    pub fn set_coalesce(&mut self, window_ms: u32, max_bytes: usize) -> Result<(), Error> {
+        if max_bytes > COALESCE_BUFF_SIZE {
+            return Err(Error::InvalidParameter);
+        }
+        if window_ms > 0 {
+            // longer than the counter can count, checked now rather than on the first send
+            let timer = self.timer.get_mut();
+            timer.start(window_ms.millis()).map_err(|_| Error::InvalidParameter)?;
+            timer.cancel().ok();
+        }
+        self.flush()?;
+        self.coalesce.window = window_ms;
+        self.coalesce.max_bytes = max_bytes;
+        Ok(())
+    }
+
+    pub fn send_coalesced(&mut self, data: &[u8]) -> Result<(), Error> {
+        if self.coalesce.window == 0 || data.len() > self.coalesce.max_bytes {
+            self.flush()?;
+            return self.cip_send_bytes(data);
+        }
+        if self.coalesce.staged.len() + data.len() > self.coalesce.max_bytes {
+            self.flush()?;
+        }
+        if self.coalesce.staged.is_empty() {
+            self.timer.get_mut().start(self.coalesce.window.millis()).map_err(|_| Error::InvalidParameter)?;
+            self.coalesce.window_lost = false;
+        }
+        self.coalesce.staged.extend_from_slice(data).ok();
+        if self.coalesce.staged.len() >= self.coalesce.max_bytes {
+            return self.flush();
+        }
+        self.poll_coalesce()
+    }
+
+    // sends the staged payloads once their window is over, call it regularly while staging
+    pub fn poll_coalesce(&mut self) -> Result<(), Error> {
+        if self.coalesce.staged.is_empty() {
+            return Ok(());
+        }
+        if self.coalesce.window_lost || self.timer.get_mut().wait().is_ok() {
+            return self.flush();
+        }
+        Ok(())
+    }
+
+    // sends whatever is staged right away, the staged bytes are gone even if that fails
+    pub fn flush(&mut self) -> Result<(), Error> {
+        if self.coalesce.staged.is_empty() {
+            return Ok(());
+        }
+        let staged = core::mem::take(&mut self.coalesce.staged);
+        self.coalesce.window_lost = false;
+        self.cip_send_bytes(&staged)
+    }
+
+    // for peers speaking 4 byte big endian length + payload in both directions: sends `payload`
+    // framed that way and returns the payload of the answer, however many +IPD frames it spans
+    pub fn request_framed<const N: usize>(&mut self, payload: &[u8], timeout: u32) -> Result<Vec<u8, N>, Error> {
+        let len = u32::try_from(payload.len()).map_err(|_| Error::InvalidParameter)?;
+        self.cip_send_bytes(&len.to_be_bytes())?;
+        if !payload.is_empty() {
+            self.cip_send_bytes(payload)?;
+        }
+        self.receiver().receive_framed(timeout)
+    }
+
+    pub fn cip_close(&mut self) -> Result<bool, Error> {
+        self.send("AT+CIPCLOSE");
+        let receive = self.recv_string_with_flag(self.ok(), 3000_u32)?;
+        Ok(receive.ends_with(self.ok()))
+    }
+
+    // mux mode, 5 closes every link
+    pub fn cip_close_mux(&mut self, link_id: u8) -> Result<bool, Error> {
+        if link_id > MAX_LINK_ID + 1 {
+            return Err(Error::InvalidParameter);
+        }
+        let mut command = String::<ESP_TX_BUFF_SIZE>::new();
+        write!(command, "AT+CIPCLOSE={}", link_id).ok();
+        self.send(&command);
+        let receive = self.recv_string_with_flag(self.ok(), 3000_u32)?;
+        Ok(receive.ends_with(self.ok()))
+    }
+
+    // multiple connections are needed for start_server and the *_mux calls, the module only
+    // accepts the switch while no connection is open
+    pub fn set_mux(&mut self, enable: bool) -> Result<bool, Error> {
+        let mut command = String::<ESP_TX_BUFF_SIZE>::new();
+        write!(command, "AT+CIPMUX={}", enable as u8).ok();
+        self.send(&command);
+        let receive = self.recv_string_with_flag(self.ok(), 3000_u32)?;
+        if receive.ends_with(self.ok()) {
+            self.config.mux = enable;
+        }
+        Ok(receive.ends_with(self.ok()))
+    }
+
+    // listens on `port`, clients show up as LinkConnected/LinkClosed events
+    pub fn start_server(&mut self, port: u16) -> Result<bool, Error> {
+        if !self.config.mux {
+            return Err(Error::InvalidParameter);
+        }
+        let mut command = String::<ESP_TX_BUFF_SIZE>::new();
+        write!(command, "AT+CIPSERVER=1,{}", port).ok();
+        self.send(&command);
+        let receive = self.recv_string_with_flag(self.ok(), 3000_u32)?;
+        Ok(receive.ends_with(self.ok()))
+    }
+
+    pub fn stop_server(&mut self) -> Result<bool, Error> {
+        self.send("AT+CIPSERVER=0");
+        let receive = self.recv_string_with_flag(self.ok(), 3000_u32)?;
+        Ok(receive.ends_with(self.ok()))
+    }
+
+    // application level round trip in milliseconds over the open connection; the remote end has
+    // to echo (or otherwise answer) the few bytes sent, e.g. a TCP echo service
+    pub fn server_rtt(&mut self) -> Result<u32, Error> {
+        const PROBE: &[u8] = b"ping";
+        let mut command = String::<ESP_TX_BUFF_SIZE>::new();
+        write!(command, "AT+CIPSEND={}", PROBE.len()).ok();
+        self.send(&command);
+        let receive = self.recv_string_with_flag(self.prompt(), 3000_u32)?;
+        if !receive.ends_with(self.prompt()) {
+            return Err(Error::Failure);
+        }
+        self.sender().write_bytes(PROBE);
+        let (reply, elapsed) = self.receiver().collect_ipd::<MAX_STRING_SIZE>(1, 5000_u32);
+        reply?;
+        Ok(elapsed)
+    }
+
+    pub fn is_tx_busy(&mut self) -> Result<bool, Error> {
+        self.send("AT+CIPBUFSTATUS");
+        let receive = match self.recv_string_with_flag(self.ok(), 1000_u32) {
+            Ok(receive) => receive,
+            // the module answers "busy s..." instead of OK while it is still pushing data out
+            Err(Error::Busy) => return Ok(true),
+            // firmware without CIPBUFSTATUS, fall back to the last tracked CIPSEND confirmation
+            Err(Error::Failure) => return Ok(self.rx_state.send_pending),
+            Err(error) => return Err(error),
+        };
+        for line in receive.lines() {
+            // <next segment id>,<sent segment id>,<acked segment id>,<remain buffer size>,<queue number>
+            let data_str = line.strip_prefix("+CIPBUFSTATUS:").unwrap_or(line);
+            let (next, rest) = next_field(data_str);
+            let (sent, rest) = next_field(rest);
+            let (acked, rest) = next_field(rest);
+            let (_, rest) = next_field(rest);
+            let (queue, _) = next_field(rest);
+            if let (Ok(next), Ok(sent), Ok(acked), Ok(queue)) =
+                (next.parse::<u32>(), sent.parse::<u32>(), acked.parse::<u32>(), queue.parse::<u32>())
+            {
+                self.rx_state.send_pending = queue > 0 || sent.wrapping_add(1) != next || acked != sent;
+                return Ok(self.rx_state.send_pending);
+            }
+        }
+        Ok(self.rx_state.send_pending)
+    }
+
+    pub fn cip_receive(&mut self) -> Result<String<MAX_STRING_SIZE>, Error> {
+        self.receiver().cip_receive()
+    }
+
+    // mux mode, the payload together with the link it came in on
+    pub fn cip_receive_mux(&mut self) -> Result<(u8, String<MAX_STRING_SIZE>), Error> {
+        self.receiver().cip_receive_mux()
+    }
+
+    // keeps collecting +IPD payload bytes until at least `min` arrived or the timeout fires,
+    // a result shorter than `min` means the timeout fired first
+    pub fn receive_at_least<const N: usize>(&mut self, min: usize, timeout: u32) -> Result<Vec<u8, N>, Error> {
+        self.receiver().receive_at_least(min, timeout)
+    }
+
+    pub fn receive_frame<const N: usize>(&mut self, timeout: u32) -> Result<Frame<N>, Error> {
+        self.receiver().receive_frame(timeout)
     }
 
     pub fn cip_status(&mut self) -> Result<CipStatus, Error> {
         self.send("AT+CIPSTATUS");
-        let receive = self.recv_string_with_flag("OK", 3000_u32)?;
+        let receive = self.recv_string_with_flag(self.ok(), 3000_u32)?;
         for line in receive.lines() {
             if line.starts_with("STATUS:") {
                 if let Some(pos) = line.find(':') {
@@ -164,7 +1300,7 @@ where
                         "3" => CipStatus::ServerConnected,
                         "4" => CipStatus::ServerDisconnected,
                         "5" => CipStatus::WifiConnectFailed,
-                        _ => panic!("error")
+                        _ => return Err(Error::UnexpectedResponse),
                     };
                     return Ok(cip_status);
                 }
@@ -173,42 +1309,945 @@ where
         Err(Error::NoResponse)
     }
 
+    // the endpoint the current connection actually landed on, after DNS resolution
+    pub fn remote_address(&mut self) -> Result<(String<15>, u16), Error> {
+        self.send("AT+CIPSTATUS");
+        let receive = self.recv_string_with_flag(self.ok(), 3000_u32)?;
+        for line in receive.lines() {
+            // +CIPSTATUS:<link id>,<type>,<remote ip>,<remote port>,<local port>,<tetype>
+            if let Some(data_str) = line.strip_prefix("+CIPSTATUS:") {
+                let (_, rest) = next_field(data_str);
+                let (_, rest) = next_field(rest);
+                let (ip, rest) = next_field(rest);
+                let (port, _) = next_field(rest);
+                let ip = String::from_str(ip).map_err(|_| Error::Failure)?;
+                let port = port.parse().map_err(|_| Error::Failure)?;
+                return Ok((ip, port));
+            }
+        }
+        Err(Error::NotConnected)
+    }
+
+    // the station's address, "0.0.0.0" until DHCP handed one out. Without station lines (soft AP
+    // only mode) there is none to report
+    pub fn local_address(&mut self) -> Result<IpInfo, Error> {
+        self.send("AT+CIFSR");
+        let receive = self.recv_string_with_flag(self.ok(), 3000_u32)?;
+        let mut info = IpInfo::default();
+        let mut station = false;
+        for line in receive.lines() {
+            if let Some(data_str) = line.strip_prefix("+CIFSR:STAIP,") {
+                info.ip = String::from_str(next_field(data_str).0).map_err(|_| Error::UnexpectedResponse)?;
+                station = true;
+            } else if let Some(data_str) = line.strip_prefix("+CIFSR:STAMAC,") {
+                info.mac = String::from_str(next_field(data_str).0).map_err(|_| Error::UnexpectedResponse)?;
+                station = true;
+            }
+        }
+        if !station {
+            return Err(Error::NotConnected);
+        }
+        Ok(info)
+    }
+
+    pub fn station_mac(&mut self) -> Result<String<18>, Error> {
+        self.send("AT+CIPSTAMAC?");
+        let receive = self.recv_string_with_flag(self.ok(), 3000_u32)?;
+        for line in receive.lines() {
+            if let Some(data_str) = line.strip_prefix("+CIPSTAMAC:") {
+                return String::from_str(next_field(data_str).0).map_err(|_| Error::Failure);
+            }
+        }
+        Err(Error::NoResponse)
+    }
+
+    // +CWJAP:<ssid>,<bssid>,<channel>,<rssi>[,...], ESP-AT 2.x continues with its join options;
+    // no AT firmware reports a noise floor
+    pub fn link_quality(&mut self) -> Result<LinkQuality, Error> {
+        self.send("AT+CWJAP?");
+        let receive = self.recv_string_with_flag(self.ok(), 3000_u32)?;
+        for line in receive.lines() {
+            if let Some(data_str) = line.strip_prefix("+CWJAP:") {
+                let (_ssid, rest) = next_field(data_str);
+                let (_bssid, rest) = next_field(rest);
+                let (channel, rest) = next_field(rest);
+                let channel = channel.parse().map_err(|_| Error::UnexpectedResponse)?;
+                let rssi = next_field(rest).0.parse().map_err(|_| Error::UnexpectedResponse)?;
+                return Ok(LinkQuality { rssi, channel });
+            }
+        }
+        // no +CWJAP line means the station isn't associated
+        Err(Error::NotConnected)
+    }
+
+    // +CWDHCP:<state> for whether DHCP is on, the addresses it handed out from +CIPSTA; neither
+    // ESP8266 NonOS AT nor ESP-AT report the lease time or the DHCP server
+    pub fn dhcp_lease(&mut self) -> Result<DhcpLease, Error> {
+        let mut lease = DhcpLease::default();
+        let receive = self.expect_prefix("AT+CWDHCP?", "+CWDHCP", 3000_u32)?;
+        for line in receive.lines() {
+            // 1.x firmware reports +CWDHCP_CUR
+            if let Some((_, data_str)) = line.strip_prefix("+CWDHCP").and_then(|line| line.split_once(':')) {
+                lease.state = next_field(data_str).0.parse().map_err(|_| Error::UnexpectedResponse)?;
+            }
+        }
+        let receive = self.expect_prefix("AT+CIPSTA?", "+CIPSTA", 3000_u32)?;
+        for line in receive.lines() {
+            // +CIPSTA[_CUR]:<ip|gateway|netmask>:"<address>"
+            let Some((_, data_str)) = line.strip_prefix("+CIPSTA").and_then(|line| line.split_once(':')) else {
+                continue;
+            };
+            let (field, address) = match data_str.split_once(':') {
+                Some(("ip", address)) => (&mut lease.ip, address),
+                Some(("gateway", address)) => (&mut lease.gateway, address),
+                Some(("netmask", address)) => (&mut lease.netmask, address),
+                _ => continue,
+            };
+            *field = String::from_str(next_field(address).0).map_err(|_| Error::UnexpectedResponse)?;
+        }
+        Ok(lease)
+    }
+
+    // the station MAC packed big endian into the low 48 bits
+    pub fn device_id(&mut self) -> Result<u64, Error> {
+        let octets = parse_mac(&self.station_mac()?)?;
+        Ok(octets.iter().fold(0, |id, octet| id << 8 | *octet as u64))
+    }
+
+    // the firmware reports the measured rate, e.g. 115273 for a 115200 link
+    pub fn get_baud(&mut self) -> Result<u32, Error> {
+        self.send("AT+UART_CUR?");
+        let receive = self.recv_string_with_flag(self.ok(), 3000_u32)?;
+        for line in receive.lines() {
+            if let Some(data_str) = line.strip_prefix("+UART_CUR:") {
+                let (baud, _) = next_field(data_str);
+                return baud.parse().map_err(|_| Error::Failure);
+            }
+        }
+        Err(Error::NoResponse)
+    }
+
+    // 8N1 without flow control, the module acknowledges at the old rate before switching
+    // and the setting is lost on reset
+    pub fn set_baud(&mut self, baud: u32) -> Result<bool, Error> {
+        if !(110..=4608000).contains(&baud) {
+            return Err(Error::InvalidParameter);
+        }
+        let mut command = String::<ESP_TX_BUFF_SIZE>::new();
+        write!(command, "AT+UART_CUR={},8,1,0,0", baud).ok();
+        self.send(&command);
+        let receive = self.recv_string_with_flag(self.ok(), 3000_u32)?;
+        if receive.ends_with(self.ok()) {
+            self.config.baud = baud;
+        }
+        Ok(receive.ends_with(self.ok()))
+    }
+
+    // steps the baud rate down while more than BAUD_TUNE_THRESHOLD bytes get dropped (or more than
+    // BAUD_TUNE_MAX_FAILURES probes fail) over BAUD_TUNE_PROBES AT probes; `reconfigure` has to switch
+    // the STM32 USART to the given rate (e.g. via Serial::reconfigure). Returns the rate it settled on,
+    // on failure the module and the USART go back to the rate they started at
+    pub fn auto_tune_baud(&mut self, mut reconfigure: impl FnMut(u32)) -> Result<u32, Error> {
+        // the firmware reports the measured rate, the nearest step is what the link runs at
+        let measured = self.get_baud()?;
+        let start = *BAUD_STEPS.iter().min_by_key(|step| step.abs_diff(measured)).unwrap();
+        let mut baud = start;
+        let outcome = loop {
+            self.reset_dropped_count();
+            let failures = (0..BAUD_TUNE_PROBES).filter(|_| !matches!(self.test(), Ok(true))).count();
+            if self.dropped_byte_count() <= BAUD_TUNE_THRESHOLD && failures <= BAUD_TUNE_MAX_FAILURES {
+                self.reset_dropped_count();
+                break Ok(baud);
+            }
+            let Some(&next) = BAUD_STEPS.iter().find(|step| **step < baud) else {
+                break Err(Error::Failure);
+            };
+            match self.set_baud(next) {
+                Ok(true) => {
+                    reconfigure(next);
+                    baud = next;
+                }
+                Ok(false) => break Err(Error::Failure),
+                Err(error) => break Err(error),
+            }
+        };
+        if outcome.is_err() && baud != start {
+            if let Ok(true) = self.set_baud(start) {
+                reconfigure(start);
+            }
+        }
+        outcome
+    }
+
+    // round trip time in milliseconds
+    pub fn ping(&mut self, host: &str) -> Result<u32, Error> {
+        let mut command = String::<ESP_TX_BUFF_SIZE>::new();
+        write!(command, "AT+PING=\"{}\"", host).ok();
+        self.send(&command);
+        let receive = self.recv_string_with_flag(self.ok(), 5000_u32)?;
+        for line in receive.lines() {
+            // "+PING:<time>" on ESP-AT, a bare "+<time>" on older firmware
+            let data_str = line.strip_prefix("+PING:").or_else(|| line.strip_prefix('+'));
+            if let Some(time) = data_str.and_then(|time| time.trim().parse().ok()) {
+                return Ok(time);
+            }
+        }
+        Err(Error::NoResponse)
+    }
+
+    pub fn ping_multi(&mut self, host: &str, count: u8, interval_ms: u32) -> Result<PingStats, Error> {
+        if count == 0 {
+            return Err(Error::InvalidParameter);
+        }
+        let mut stats = PingStats { min: u32::MAX, ..PingStats::default() };
+        let mut total = 0_u32;
+        for i in 0..count {
+            if i > 0 && interval_ms > 0 {
+                self.pause(interval_ms);
+            }
+            stats.sent += 1;
+            if let Ok(time) = self.ping(host) {
+                stats.received += 1;
+                stats.min = stats.min.min(time);
+                stats.max = stats.max.max(time);
+                total += time;
+            }
+        }
+        if stats.received == 0 {
+            stats.min = 0;
+        } else {
+            stats.avg = total / stats.received as u32;
+        }
+        Ok(stats)
+    }
+
+    // 0 disables sleep, 1 light sleep, 2 modem sleep
+    pub fn set_sleep_mode(&mut self, mode: u8) -> Result<bool, Error> {
+        if mode > 2 {
+            return Err(Error::InvalidParameter);
+        }
+        let mut command = String::<ESP_TX_BUFF_SIZE>::new();
+        write!(command, "AT+SLEEP={}", mode).ok();
+        self.send(&command);
+        let receive = self.recv_string_with_flag(self.ok(), 3000_u32)?;
+        Ok(receive.ends_with(self.ok()))
+    }
+
+    pub fn get_sleep_mode(&mut self) -> Result<u8, Error> {
+        self.send("AT+SLEEP?");
+        let receive = self.recv_string_with_flag(self.ok(), 3000_u32)?;
+        for line in receive.lines() {
+            if let Some(mode) = line.strip_prefix("+SLEEP:") {
+                return mode.trim().parse().map_err(|_| Error::Failure);
+            }
+        }
+        Err(Error::NoResponse)
+    }
+
+    // raw 10 bit reading of the TOUT pin (0-1V), firmware without AT+SYSADC answers ERROR
+    pub fn read_adc(&mut self) -> Result<u16, Error> {
+        self.send("AT+SYSADC?");
+        let receive = match self.recv_string_with_flag(self.ok(), 3000_u32) {
+            Err(Error::Failure) => return Err(Error::Unsupported),
+            receive => receive?,
+        };
+        for line in receive.lines() {
+            if let Some(value) = line.strip_prefix("+SYSADC:") {
+                return match value.trim().parse() {
+                    Ok(value) if value <= 1024 => Ok(value),
+                    _ => Err(Error::UnexpectedResponse),
+                };
+            }
+        }
+        Err(Error::NoResponse)
+    }
+
     pub fn set_auto_join_ap(&mut self, mode: u8) -> Result<bool, Error> {
         let mut command = String::<ESP_TX_BUFF_SIZE>::new();
         write!(command, "AT+CWAUTOCONN={}", mode).ok();
         self.send(&command);
-        let receive = self.recv_string_with_flag("OK", 3000_u32)?;
-        Ok(receive == "OK")
+        let receive = self.recv_string_with_flag(self.ok(), 3000_u32)?;
+        Ok(status_line(&receive) == self.ok())
+    }
+
+    // interval 0-7200 s (0 disables reconnecting), max_attempts 0-1000 (0 retries forever).
+    // ESP-AT 2.x only, validated arguments that still get ERROR mean the firmware lacks it
+    pub fn set_reconnect_config(&mut self, interval_secs: u16, max_attempts: u16) -> Result<bool, Error> {
+        if interval_secs > 7200 || max_attempts > 1000 {
+            return Err(Error::InvalidParameter);
+        }
+        let mut command = String::<ESP_TX_BUFF_SIZE>::new();
+        write!(command, "AT+CWRECONNCFG={},{}", interval_secs, max_attempts).ok();
+        self.send(&command);
+        match self.recv_string_with_flag(self.ok(), 3000_u32) {
+            Ok(receive) => Ok(receive.ends_with(self.ok())),
+            Err(Error::Failure) => Err(Error::Unsupported),
+            Err(error) => Err(error),
+        }
     }
 
     pub fn join_ap(&mut self, ssid: &str, password: &str) -> Result<bool, Error> {
+        self.join_ap_with_timeout(ssid, password, 5000_u32)
+    }
+
+    // tries the networks in order and returns the index of the first one joined
+    pub fn join_prioritized(&mut self, networks: &[(&str, &str)], per_attempt_timeout: u32) -> Result<usize, Error> {
+        for (index, (ssid, password)) in networks.iter().enumerate() {
+            match self.join_ap_with_timeout(ssid, password, per_attempt_timeout) {
+                Ok(true) => return Ok(index),
+                // a join that is still running would answer "busy p..." to everything, abort it first
+                Err(Error::NoResponse) | Err(Error::Timeout) => {
+                    self.quit_ap().ok();
+                }
+                _ => {}
+            }
+        }
+        Err(Error::Failure)
+    }
+
+    // disconnects from the AP, also aborts a pending join
+    pub fn quit_ap(&mut self) -> Result<bool, Error> {
+        for _ in 0..3 {
+            self.send("AT+CWQAP");
+            if let Ok(receive) = self.recv_string_with_flag(self.ok(), 2000_u32) {
+                return Ok(receive.ends_with(self.ok()));
+            }
+        }
+        Err(Error::NoResponse)
+    }
+
+    fn join_ap_with_timeout(&mut self, ssid: &str, password: &str, timeout: u32) -> Result<bool, Error> {
         let mut command = String::<ESP_TX_BUFF_SIZE>::new();
         write!(command, "AT+CWJAP=\"{}\",\"{}\"", ssid, password).ok();
         self.send(&command);
-        let receive = self.recv_string_with_flag("OK", 5000_u32)?;
-        Ok(receive.ends_with("OK"))
+        let receive = self.recv_string_with_flag(self.ok(), timeout)?;
+        if receive.ends_with(self.ok()) {
+            self.config.ssid = String::from_str(ssid).unwrap_or_default();
+            self.config.password = String::from_str(password).unwrap_or_default();
+        }
+        Ok(receive.ends_with(self.ok()))
+    }
+
+    // call from the main loop: after a WIFI DISCONNECT or CLOSED it rejoins the AP and reconnects to
+    // the server, returns whether the link is up. Failed attempts skip 2, 4, ... up to 64 following ticks.
+    // It doesn't read the USART itself, reading it here would throw away +IPD data; the flags are
+    // latched by the application's own receive and poll_events calls
+    pub fn maintain_connection(&mut self, ssid: &str, password: &str, ip: &str, port: u16) -> Result<bool, Error> {
+        if !self.rx_state.wifi_lost && !self.rx_state.link_lost {
+            return Ok(true);
+        }
+        if self.reconnect_backoff > 0 {
+            self.reconnect_backoff -= 1;
+            return Ok(false);
+        }
+        match self.reconnect(ssid, password, ip, port) {
+            Ok(true) => {
+                self.rx_state.wifi_lost = false;
+                self.rx_state.link_lost = false;
+                self.reconnect_failures = 0;
+                Ok(true)
+            }
+            result => {
+                self.reconnect_failures = self.reconnect_failures.saturating_add(1);
+                self.reconnect_backoff = 1 << self.reconnect_failures.min(6);
+                result
+            }
+        }
+    }
+
+    fn reconnect(&mut self, ssid: &str, password: &str, ip: &str, port: u16) -> Result<bool, Error> {
+        // the module may have recovered on its own (auto connect) or the event was stale
+        match self.cip_status()? {
+            CipStatus::ServerConnected => return Ok(true),
+            CipStatus::WifiConnected | CipStatus::ServerDisconnected => {}
+            _ => {
+                if !self.join_ap(ssid, password)? {
+                    return Ok(false);
+                }
+            }
+        }
+        let protocol = self.config.protocol.clone();
+        let protocol = if protocol.is_empty() { "TCP" } else { protocol.as_str() };
+        self.connect_server(protocol, ip, port)
+    }
+
+    // pci_en refuses WEP/open APs and reconn_interval (0-7200 s, 0 disables) controls reconnecting
+    // after a drop. Only ESP-AT 2.x understands these; older firmware gets the plain two argument
+    // join when pci_en is off (reconn_interval can't be set there) and Unsupported when it is on.
+    // An ERROR from 2.x, e.g. pci_en refusing the AP, is returned as Failure and never retried
+    pub fn join_ap_with_options(&mut self, ssid: &str, password: &str, pci_en: bool, reconn_interval: u16) -> Result<bool, Error> {
+        if reconn_interval > 7200 {
+            return Err(Error::InvalidParameter);
+        }
+        if self.at_version_major()? < 2 {
+            if pci_en {
+                return Err(Error::Unsupported);
+            }
+            return self.join_ap(ssid, password);
+        }
+        let mut command = String::<ESP_TX_BUFF_SIZE>::new();
+        write!(command, "AT+CWJAP=\"{}\",\"{}\",,{},{}", ssid, password, pci_en as u8, reconn_interval).ok();
+        self.send(&command);
+        let receive = self.recv_string_with_flag(self.ok(), 5000_u32)?;
+        if receive.ends_with(self.ok()) {
+            self.config.ssid = String::from_str(ssid).unwrap_or_default();
+            self.config.password = String::from_str(password).unwrap_or_default();
+        }
+        Ok(receive.ends_with(self.ok()))
+    }
+
+    // from "AT version:2.2.0.0(...)" in AT+GMR, asked once and remembered
+    pub fn at_version_major(&mut self) -> Result<u8, Error> {
+        if let Some(major) = self.at_major {
+            return Ok(major);
+        }
+        let receive = self.expect_prefix("AT+GMR", "AT version:", 3000_u32)?;
+        for line in receive.lines() {
+            if let Some(version) = line.strip_prefix("AT version:") {
+                let major = version.split('.').next().unwrap_or("").trim();
+                let major = major.parse().map_err(|_| Error::UnexpectedResponse)?;
+                self.at_major = Some(major);
+                return Ok(major);
+            }
+        }
+        Err(Error::NoResponse)
+    }
+
+    // access points that don't fit into N are left out; a scan takes a few seconds, more with
+    // many networks around. Lines are parsed as they arrive so the response may be of any length
+    pub fn scan_aps<const N: usize>(&mut self, timeout: u32) -> Result<Vec<ApInfo, N>, Error> {
+        self.send("AT+CWLAP");
+        let mut aps = Vec::new();
+        self.receiver().read_lines(timeout, |line| {
+            if let Some(data_str) = line.strip_prefix("+CWLAP:(").and_then(|line| line.strip_suffix(')')) {
+                if let Ok(ap) = data_str.parse() {
+                    aps.push(ap).ok();
+                }
+            }
+        })?;
+        Ok(aps)
+    }
+
+    // joins the strongest open network in range and returns its SSID
+    pub fn join_any_open(&mut self) -> Result<String<32>, Error> {
+        let aps = self.scan_aps::<16>(10000_u32)?;
+        let strongest = aps
+            .into_iter()
+            .filter(|ap| ap.encryption == Encryption::Open && !ap.ssid.is_empty())
+            .max_by_key(|ap| ap.rssi)
+            .ok_or(Error::NotFound)?;
+        if !self.join_ap(&strongest.ssid, "")? {
+            return Err(Error::Failure);
+        }
+        Ok(strongest.ssid)
+    }
+
+    pub fn configure_soft_ap_full(
+        &mut self,
+        ssid: &str,
+        password: &str,
+        channel: u8,
+        encryption: u8,
+        max_conn: u8,
+        hidden: u8,
+    ) -> Result<bool, Error> {
+        // encryption: 0 OPEN, 2 WPA_PSK, 3 WPA2_PSK, 4 WPA_WPA2_PSK (WEP is not supported by soft-AP)
+        if ssid.is_empty() || ssid.len() > 32 || !(1..=14).contains(&channel) {
+            return Err(Error::InvalidParameter);
+        }
+        if !matches!(encryption, 0 | 2 | 3 | 4) || !(1..=4).contains(&max_conn) || hidden > 1 {
+            return Err(Error::InvalidParameter);
+        }
+        if encryption != 0 && !(8..=64).contains(&password.len()) {
+            return Err(Error::InvalidParameter);
+        }
+        let mut command = String::<ESP_TX_BUFF_SIZE>::new();
+        write!(
+            command,
+            "AT+CWSAP=\"{}\",\"{}\",{},{},{},{}",
+            ssid, password, channel, encryption, max_conn, hidden
+        )
+        .ok();
+        self.send(&command);
+        let receive = self.recv_string_with_flag(self.ok(), 5000_u32)?;
+        Ok(receive.ends_with(self.ok()))
+    }
+
+    pub fn soft_ap_config(&mut self) -> Result<SoftApConfig, Error> {
+        self.send("AT+CWSAP?");
+        let receive = self.recv_string_with_flag(self.ok(), 3000_u32)?;
+        for line in receive.lines() {
+            if let Some(data_str) = line.strip_prefix("+CWSAP:") {
+                let (ssid, rest) = next_field(data_str);
+                let (password, rest) = next_field(rest);
+                let (channel, rest) = next_field(rest);
+                let (encryption, rest) = next_field(rest);
+                // older firmware stops after the encryption field
+                let (max_conn, rest) = next_field(rest);
+                let (hidden, _) = next_field(rest);
+                return Ok(SoftApConfig {
+                    ssid: String::from_str(ssid).map_err(|_| Error::Failure)?,
+                    password: String::from_str(password).map_err(|_| Error::Failure)?,
+                    channel: channel.parse().map_err(|_| Error::Failure)?,
+                    encryption: encryption.parse().map_err(|_| Error::Failure)?,
+                    max_conn: max_conn.parse().unwrap_or(4),
+                    hidden: hidden.parse().unwrap_or(0),
+                });
+            }
+        }
+        Err(Error::NoResponse)
+    }
+
+    // runs a query and insists on a line starting with `prefix`, e.g. "+CWMODE:"
+    pub fn expect_prefix(&mut self, command: &str, prefix: &str, timeout: u32) -> Result<String<MAX_STRING_SIZE>, Error> {
+        self.send(command);
+        let receive = self.recv_string_with_flag(self.ok(), timeout)?;
+        if receive.lines().any(|line| line.starts_with(prefix)) {
+            Ok(receive)
+        } else {
+            Err(Error::UnexpectedResponse)
+        }
     }
 
     pub fn recv_string(&mut self, success_flag: &str) -> Result<String<1024>, Error> {
-        return self.recv_string_with_flag(success_flag, 1000_u32);
+        self.recv_string_with_flag(success_flag, 1000_u32)
     }
 
     pub fn recv_string_with_timeout(&mut self, timeout: u32) -> Result<String<MAX_STRING_SIZE>, Error> {
-        return self.recv_string_with_flag("", timeout);
+        self.recv_string_with_flag("", timeout)
     }
 
     pub fn recv_string_with_flag(&mut self, success_flag: &str, timeout: u32) -> Result<String<MAX_STRING_SIZE>, Error> {
+        // while a time budget is active no single receive may outlast what is left of it
+        let (timeout, clamped) = match self.budget {
+            Some(0) => return Err(Error::Timeout),
+            Some(budget) if budget < timeout => (budget, true),
+            _ => (timeout, false),
+        };
+        let (status, elapsed) = self.receiver().read_until(success_flag, timeout);
+        if let Some(budget) = self.budget.as_mut() {
+            *budget = budget.saturating_sub(elapsed);
+        }
+        match status {
+            Err(Error::NoResponse) if clamped => Err(Error::Timeout),
+            status => status,
+        }
+    }
+}
+
+pub struct Sender<'s, 'a, USART, TIM, const FREQ: u32, const TRACE: usize = 0> {
+    tx: &'s mut Tx<USART>,
+    timer: &'s RefCell<&'a mut Counter<TIM, FREQ>>,
+    trace: &'s RefCell<Trace<TRACE>>,
+    echo: &'s RefCell<Echo>,
+}
+
+impl<'s, 'a, USART, TIM, const FREQ: u32, const TRACE: usize> Sender<'s, 'a, USART, TIM, FREQ, TRACE>
+where
+    USART: stm32f1xx_hal::serial::Instance,
+    TIM: stm32f1xx_hal::timer::Instance,
+{
+    pub fn send(&mut self, command: &str) -> bool {
+        let mut echo = self.echo.borrow_mut();
+        echo.sent.clear();
+        if echo.enabled {
+            // a command too long to remember just goes unchecked and keeps its echo
+            echo.sent.push_str(command).ok();
+        }
+        drop(echo);
+        let mut base: String<4096> = String::new();
+        if base.push_str(command).is_err() || base.push_str("\r\n").is_err() {
+            return false;
+        }
+        let command = base.as_str();
+        self.trace.borrow_mut().record(command.as_bytes());
+        self.tx.write_str(command).unwrap();
+        true
+    }
+
+    pub fn write_bytes(&mut self, data: &[u8]) {
+        self.trace.borrow_mut().record(data);
+        self.tx.bwrite_all(data).ok();
+    }
+
+    // leaves transparent transmission, the module only accepts +++ with silence around it
+    pub fn send_escape(&mut self) {
+        self.pause(20);
+        self.trace.borrow_mut().record(b"+++");
+        self.tx.write_str("+++").ok();
+        self.pause(1000);
+    }
+
+    fn pause(&mut self, millis: u32) {
+        let mut timer = self.timer.borrow_mut();
+        if timer.start(millis.millis()).is_err() {
+            // longer than the counter can count in one go
+            drop(timer);
+            if millis > 1 {
+                self.pause(millis / 2);
+                self.pause(millis - millis / 2);
+            }
+            return;
+        }
+        while timer.wait().is_err() {}
+        timer.cancel().ok();
+    }
+}
+
+pub struct Receiver<'s, 'a, USART, TIM, const FREQ: u32, const TRACE: usize = 0> {
+    rx: &'s mut Rx<USART>,
+    timer: &'s RefCell<&'a mut Counter<TIM, FREQ>>,
+    trace: &'s RefCell<Trace<TRACE>>,
+    echo: &'s RefCell<Echo>,
+    state: &'s mut RxState,
+}
+
+impl<'s, 'a, USART, TIM, const FREQ: u32, const TRACE: usize> Receiver<'s, 'a, USART, TIM, FREQ, TRACE>
+where
+    USART: stm32f1xx_hal::serial::Instance,
+    TIM: stm32f1xx_hal::timer::Instance,
+{
+    pub fn recv_string_with_timeout(&mut self, timeout: u32) -> Result<String<MAX_STRING_SIZE>, Error> {
+        self.recv_string_with_flag("", timeout)
+    }
+
+    pub fn recv_string_with_flag(&mut self, success_flag: &str, timeout: u32) -> Result<String<MAX_STRING_SIZE>, Error> {
+        self.read_until(success_flag, timeout).0
+    }
+
+    pub fn cip_receive(&mut self) -> Result<String<MAX_STRING_SIZE>, Error> {
+        Ok(self.cip_receive_mux()?.1)
+    }
+
+    // the link id is 0 outside mux mode
+    pub fn cip_receive_mux(&mut self) -> Result<(u8, String<MAX_STRING_SIZE>), Error> {
+        let receive = self.recv_string_with_timeout(1000_u32)?;
+        for line in receive.lines() {
+            if let Some((link_id, data_str)) = parse_ipd_line(line, self.state.dialect.ipd_format()) {
+                hprintln!("receive:{}", data_str);
+                let data = String::from_str(data_str).map_err(|_| Error::BufferOverflow)?;
+                return Ok((link_id.unwrap_or(0), data));
+            }
+        }
+        Err(Error::NoResponse)
+    }
+
+    // keeps collecting +IPD payload bytes until at least `min` arrived or the timeout fires,
+    // a result shorter than `min` means the timeout fired first
+    pub fn receive_at_least<const N: usize>(&mut self, min: usize, timeout: u32) -> Result<Vec<u8, N>, Error> {
+        self.collect_ipd(min, timeout).0
+    }
+
+    // receive_at_least together with the milliseconds it took
+    fn collect_ipd<const N: usize>(&mut self, min: usize, timeout: u32) -> (Result<Vec<u8, N>, Error>, u32) {
+        let mut data: Vec<u8, N> = Vec::new();
+        let mut parser = IpdParser::new(self.state.dialect.ipd_format());
+        let mut full = false;
+        let timer_cell = self.timer;
+        let mut timer = timer_cell.borrow_mut();
+        if timer.start(timeout.millis()).is_err() {
+            return (Err(Error::InvalidParameter), 0);
+        }
+        let mut timed_out = false;
+        // always finish the current frame so its tail doesn't leak into the next read
+        while parser.in_frame() || (data.len() < min && !full) {
+            match self.read_byte() {
+                Some(byte) => {
+                    let payload = parser.feed(byte);
+                    if let Some(line) = parser.line() {
+                        self.state.capture_events(line);
+                    }
+                    if let Some(byte) = payload {
+                        if data.push(byte).is_err() {
+                            full = true;
+                            self.state.dropped += 1;
+                        }
+                    }
+                }
+                None => {
+                    if timer.wait().is_ok() {
+                        timed_out = true;
+                        break;
+                    }
+                    self.state.idle();
+                }
+            }
+        }
+        let elapsed = if timed_out { timeout } else { ticks_to_millis::<FREQ>(timer.now().ticks()) };
+        timer.cancel().ok();
+        if data.is_empty() {
+            return (Err(Error::NoResponse), elapsed);
+        }
+        (Ok(data), elapsed)
+    }
+
+    // collects +IPD payload until `terminator` shows up, the rest of that frame is kept as well
+    pub fn receive_until<const N: usize>(&mut self, terminator: &[u8], timeout: u32) -> Result<Vec<u8, N>, Error> {
+        let mut data: Vec<u8, N> = Vec::new();
+        let mut parser = IpdParser::new(self.state.dialect.ipd_format());
+        let mut done = false;
+        let mut full = false;
+        let timer_cell = self.timer;
+        let mut timer = timer_cell.borrow_mut();
+        timer.start(timeout.millis()).map_err(|_| Error::InvalidParameter)?;
+        // finish the frame the terminator arrived in, what doesn't fit is dropped
+        while parser.in_frame() || !done {
+            match self.read_byte() {
+                Some(byte) => {
+                    let payload = parser.feed(byte);
+                    if let Some(line) = parser.line() {
+                        self.state.capture_events(line);
+                    }
+                    if let Some(byte) = payload {
+                        if data.push(byte).is_err() {
+                            full = !done;
+                            done = true;
+                            self.state.dropped += 1;
+                        }
+                        done = done || data.ends_with(terminator);
+                    }
+                }
+                None => {
+                    if timer.wait().is_ok() {
+                        break;
+                    }
+                    self.state.idle();
+                }
+            }
+        }
+        timer.cancel().ok();
+        if full {
+            return Err(Error::BufferOverflow);
+        }
+        if !done {
+            return Err(Error::NoResponse);
+        }
+        Ok(data)
+    }
+
+    // hands every line to `on_line` as it completes instead of collecting the response, up to the
+    // success terminator; lines longer than LINE_BUFF_SIZE are skipped
+    pub fn read_lines(&mut self, timeout: u32, mut on_line: impl FnMut(&str)) -> Result<(), Error> {
+        let mut line: String<LINE_BUFF_SIZE> = String::new();
+        let mut overlong = false;
+        let timer_cell = self.timer;
+        let mut timer = timer_cell.borrow_mut();
+        timer.start(timeout.millis()).map_err(|_| Error::InvalidParameter)?;
+        let status = loop {
+            match self.read_byte() {
+                Some(b'\n') => {
+                    let text = line.trim_end();
+                    // the first line may be the echo, checked like in read_until
+                    let expected = core::mem::take(&mut self.echo.borrow_mut().sent);
+                    let echoed = !expected.is_empty() && text == expected;
+                    if self.state.verify_echo && !expected.is_empty() && !echoed {
+                        break Err(Error::EchoMismatch);
+                    }
+                    if !echoed && !overlong {
+                        self.state.capture_events(text);
+                        if text == self.state.dialect.success_terminator() {
+                            break Ok(());
+                        }
+                        if self.state.dialect.error_tokens().iter().any(|token| text.ends_with(token)) {
+                            break Err(Error::Failure);
+                        }
+                        on_line(text);
+                    }
+                    line.clear();
+                    overlong = false;
+                }
+                Some(byte) => {
+                    if line.push(byte as char).is_err() {
+                        overlong = true;
+                        self.state.dropped += 1;
+                    }
+                }
+                None => {
+                    if timer.wait().is_ok() {
+                        break Err(Error::NoResponse);
+                    }
+                    self.state.idle();
+                }
+            }
+        };
+        timer.cancel().ok();
+        status
+    }
+
+    // one length prefixed message, see request_framed; a message longer than N is read to its
+    // end anyway so it doesn't leak into the next receive, then rejected
+    pub fn receive_framed<const N: usize>(&mut self, timeout: u32) -> Result<Vec<u8, N>, Error> {
+        let mut data: Vec<u8, N> = Vec::new();
+        let mut parser = IpdParser::new(self.state.dialect.ipd_format());
+        let mut header: Vec<u8, 4> = Vec::new();
+        let mut wanted = None;
+        let mut received = 0;
+        let timer_cell = self.timer;
+        let mut timer = timer_cell.borrow_mut();
+        timer.start(timeout.millis()).map_err(|_| Error::InvalidParameter)?;
+        while parser.in_frame() || wanted != Some(received) {
+            match self.read_byte() {
+                Some(byte) => {
+                    let payload = parser.feed(byte);
+                    if let Some(line) = parser.line() {
+                        self.state.capture_events(line);
+                    }
+                    let Some(byte) = payload else {
+                        continue;
+                    };
+                    if wanted.is_none() {
+                        header.push(byte).ok();
+                        if header.is_full() {
+                            wanted = Some(u32::from_be_bytes([header[0], header[1], header[2], header[3]]) as usize);
+                        }
+                    } else if wanted != Some(received) {
+                        received += 1;
+                        if data.push(byte).is_err() {
+                            self.state.dropped += 1;
+                        }
+                    }
+                }
+                None => {
+                    if timer.wait().is_ok() {
+                        break;
+                    }
+                    self.state.idle();
+                }
+            }
+        }
+        timer.cancel().ok();
+        match wanted {
+            Some(wanted) if wanted == received && wanted > N => Err(Error::BufferOverflow),
+            Some(wanted) if wanted == received => Ok(data),
+            _ => Err(Error::NoResponse),
+        }
+    }
+
+    // reads exactly one +IPD frame, together with the link it arrived on
+    pub fn receive_frame<const N: usize>(&mut self, timeout: u32) -> Result<Frame<N>, Error> {
+        let mut data: Vec<u8, N> = Vec::new();
+        let mut parser = IpdParser::new(self.state.dialect.ipd_format());
+        let mut started = false;
+        let mut full = false;
+        let timer_cell = self.timer;
+        let mut timer = timer_cell.borrow_mut();
+        timer.start(timeout.millis()).map_err(|_| Error::InvalidParameter)?;
+        let mut done = false;
+        while !done {
+            match self.read_byte() {
+                Some(byte) => {
+                    let payload = parser.feed(byte);
+                    if let Some(line) = parser.line() {
+                        self.state.capture_events(line);
+                    }
+                    if let Some(byte) = payload {
+                        if data.push(byte).is_err() {
+                            full = true;
+                            self.state.dropped += 1;
+                        }
+                    }
+                    // the header ends with the length set, the frame ends when it counted down again
+                    done = started && !parser.in_frame();
+                    started = started || parser.in_frame();
+                }
+                None => {
+                    if timer.wait().is_ok() {
+                        break;
+                    }
+                    self.state.idle();
+                }
+            }
+        }
+        timer.cancel().ok();
+        if full {
+            return Err(Error::BufferOverflow);
+        }
+        if !done {
+            return Err(Error::NoResponse);
+        }
+        Ok(Frame { link_id: parser.link_id.unwrap_or(0), data })
+    }
+
+    pub fn flush(&mut self) {
+        self.read_until("", FLUSH_QUIET_MS).0.ok();
+    }
+
+    fn read_byte(&mut self) -> Option<u8> {
+        self.state.pending.pop_front().or_else(|| self.read_usart())
+    }
+
+    // keeps a byte that is already waiting for the next read
+    fn stash(&mut self) {
+        if let Some(byte) = self.read_usart() {
+            if self.state.pending.push_back(byte).is_err() {
+                self.state.dropped += 1;
+            }
+        }
+    }
+
+    fn read_usart(&mut self) -> Option<u8> {
+        match self.rx.read() {
+            Ok(byte) => {
+                self.trace.borrow_mut().record(&[byte]);
+                Some(byte)
+            }
+            Err(nb::Error::Other(error)) => {
+                self.state.overrun |= matches!(error, serial::Error::Overrun);
+                self.state.dropped += 1;
+                None
+            }
+            Err(nb::Error::WouldBlock) => None,
+        }
+    }
+
+    // the CRLF behind a token is still on its way when read_until stops at it; left in the USART
+    // it would overrun and count as dropped. Anything else goes back for the next read
+    fn drain_line_end(&mut self, timer: &mut Counter<TIM, FREQ>) {
+        if timer.start(FLUSH_QUIET_MS.millis()).is_err() {
+            return;
+        }
+        loop {
+            match self.read_byte() {
+                Some(b'\r') => {}
+                Some(b'\n') => break,
+                Some(byte) => {
+                    self.state.pending.push_front(byte).ok();
+                    break;
+                }
+                None => {
+                    if timer.wait().is_ok() {
+                        break;
+                    }
+                    self.state.idle();
+                }
+            }
+        }
+        timer.cancel().ok();
+    }
+
+    // returns the response together with the milliseconds spent waiting for it
+    fn read_until(&mut self, success_flag: &str, timeout: u32) -> (Result<String<MAX_STRING_SIZE>, Error>, u32) {
         let with_flag = !success_flag.is_empty();
         let mut piece: [u8; RECEIVE_PIECE_LEN] = [b'\0'; RECEIVE_PIECE_LEN];
         let piece_last_index = RECEIVE_PIECE_LEN - 1;
-        let timer = &mut self.timer;
+        let timer_cell = self.timer;
+        let mut timer = timer_cell.borrow_mut();
         let mut result: String<MAX_STRING_SIZE> = String::new();
-        timer.start(timeout.millis()).unwrap();
+        if timer.start(timeout.millis()).is_err() {
+            return (Err(Error::InvalidParameter), 0);
+        }
         let mut receive_started = false;
-        loop {
-            if let Ok(byte) = self.rx.read() {
+        let mut overflowed = false;
+        let mut drain = false;
+        let mut elapsed = timeout;
+        let status = loop {
+            if let Some(byte) = self.read_byte() {
                 let c = byte as char;
-                result.push(c).unwrap();
+                // keep reading to the flag or the timeout so the rest doesn't spill into the next command
+                if result.push(c).is_err() {
+                    overflowed = true;
+                    self.state.dropped += 1;
+                }
                 for i in 0..piece_last_index {
                     piece[i] = piece[i + 1];
                 }
@@ -218,35 +2257,263 @@ where
                 if receive_started {
                     if let Ok(piece_str) = str::from_utf8(&piece) {
                         if with_flag && piece_str.ends_with(success_flag) {
+                            elapsed = ticks_to_millis::<FREQ>(timer.now().ticks());
                             timer.cancel().unwrap();
-                            break;
+                            drain = true;
+                            break Ok(());
                         }
-                        if piece_str.ends_with("Error") {
+                        if self.state.dialect.error_tokens().iter().any(|token| piece_str.ends_with(token)) {
+                            elapsed = ticks_to_millis::<FREQ>(timer.now().ticks());
                             timer.cancel().unwrap();
-                            return Err(Error::Failure);
+                            drain = true;
+                            break Err(Error::Failure);
+                        }
+                    }
+                    // sending on a closed link, the caller has to reconnect instead of retrying
+                    if tail_contains(&result, self.state.dialect.link_invalid()) {
+                        elapsed = ticks_to_millis::<FREQ>(timer.now().ticks());
+                        timer.cancel().unwrap();
+                        drain = true;
+                        break Err(Error::NotConnected);
+                    }
+                    if self.state.dialect.busy_tokens().iter().any(|token| tail_contains(&result, token)) {
+                        elapsed = ticks_to_millis::<FREQ>(timer.now().ticks());
+                        timer.cancel().unwrap();
+                        drain = true;
+                        break Err(Error::Busy);
+                    }
+                    // the prompt was accepted but the TCP layer couldn't push the data out
+                    if tail_contains(&result, self.state.dialect.send_fail()) {
+                        elapsed = ticks_to_millis::<FREQ>(timer.now().ticks());
+                        timer.cancel().unwrap();
+                        drain = true;
+                        break Err(Error::Failure);
+                    }
+                    if !self.state.expect_reboot {
+                        if let Some(cause) = crash_cause(&result) {
+                            elapsed = ticks_to_millis::<FREQ>(timer.now().ticks());
+                            timer.cancel().unwrap();
+                            drain = true;
+                            break Err(Error::ModuleCrashed(cause));
                         }
                     }
                 }
                 if timer.wait().is_ok() {
+                    // a stack dump without the reset banner behind it, the module hung while rebooting
+                    if !self.state.expect_reboot && (result.contains("Exception (") || result.contains(">>>stack>>>")) {
+                        break Err(Error::ModuleCrashed(None));
+                    }
                     if with_flag {
-                        return Err(Error::NoResponse);
+                        break Err(Error::NoResponse);
                     } else {
-                        break;
+                        break Ok(());
                     }
                 }
+                self.state.idle();
             }
+        };
+        if drain {
+            self.drain_line_end(&mut timer);
         }
-        let result_str = result.as_str();
-        let result_str: &str = Self::remove_first_line(result_str.trim_end());
-        Ok(String::from_str(result_str).unwrap())
+        self.state.capture_events(&result);
+        let expected = core::mem::take(&mut self.echo.borrow_mut().sent);
+        // where the echoed command ends, None when the response doesn't start with it
+        let first_line = result.split_once('\n').map(|(line, _)| line);
+        let echo_end = first_line
+            .filter(|line| !expected.is_empty() && line.trim_end_matches('\r') == expected)
+            .map(|line| line.len() + 1);
+        if self.state.verify_echo && !expected.is_empty() && first_line.is_some() && echo_end.is_none() {
+            return (Err(Error::EchoMismatch), elapsed);
+        }
+        if let Err(error) = status {
+            return (Err(error), elapsed);
+        }
+        if overflowed {
+            return (Err(Error::BufferOverflow), elapsed);
+        }
+        let mut result_str = result.as_str();
+        if let Some(echo_end) = echo_end.filter(|_| self.state.strip_echo) {
+            result_str = &result_str[echo_end..];
+        }
+        if self.state.trim {
+            result_str = result_str.trim();
+        }
+        (String::from_str(result_str).map_err(|_| Error::BufferOverflow), elapsed)
     }
+}
 
-    fn remove_first_line(input: &str) -> &str {
-        if let Some(pos) = input.find('\n') {
-            let result = &input[pos + 1..];
-            result.trim()
-        } else {
-            ""
+fn parse_mac(input: &str) -> Result<[u8; 6], Error> {
+    let mut octets = [0_u8; 6];
+    let mut parts = input.split(':');
+    for octet in octets.iter_mut() {
+        let part = parts.next().filter(|part| part.len() == 2).ok_or(Error::Failure)?;
+        *octet = u8::from_str_radix(part, 16).map_err(|_| Error::Failure)?;
+    }
+    if parts.next().is_some() {
+        return Err(Error::Failure);
+    }
+    Ok(octets)
+}
+
+fn fnv1a(bytes: &[u8]) -> u32 {
+    bytes.iter().fold(0x811c_9dc5, |hash, byte| (hash ^ *byte as u32).wrapping_mul(0x0100_0193))
+}
+
+fn ticks_to_millis<const FREQ: u32>(ticks: u32) -> u32 {
+    (ticks as u64 * 1000 / FREQ as u64) as u32
+}
+
+// the last non-empty line, where the status word of a response sits with or without its echo
+fn status_line(input: &str) -> &str {
+    input.lines().rev().map(str::trim).find(|line| !line.is_empty()).unwrap_or("")
+}
+
+fn tail_contains(input: &str, needle: &str) -> bool {
+    let bytes = input.as_bytes();
+    let tail = &bytes[bytes.len().saturating_sub(needle.len() + 16)..];
+    tail.windows(needle.len()).any(|window| window == needle.as_bytes())
+}
+
+// looks for the "rst cause:<n>, boot mode:..." line printed after a watchdog reset or assert,
+// only complete once something follows the number so a half received line isn't misread
+fn crash_cause(input: &str) -> Option<Option<u8>> {
+    let needle = "rst cause:";
+    let start = input.len().saturating_sub(64);
+    let tail = input.get(start..).unwrap_or(input);
+    let rest = &tail[tail.find(needle)? + needle.len()..];
+    let digits = rest.bytes().take_while(u8::is_ascii_digit).count();
+    if digits == rest.len() {
+        return None;
+    }
+    Some(rest[..digits].parse().ok())
+}
+
+// +IPD,[<id>,]<len>:<data> as one line, the link id is None outside mux mode
+fn parse_ipd_line<'l>(line: &'l str, prefix: &str) -> Option<(Option<u8>, &'l str)> {
+    let (header, data) = line.strip_prefix(prefix)?.split_once(':')?;
+    let link_id = header.split_once(',').and_then(|(link_id, _)| link_id.parse().ok());
+    Some((link_id, data))
+}
+
+// split off one comma separated field, stripping the quotes the firmware wraps strings in
+fn next_field(input: &str) -> (&str, &str) {
+    let input = input.trim_start();
+    if let Some(quoted) = input.strip_prefix('"') {
+        if let Some(end) = quoted.find('"') {
+            let rest = &quoted[end + 1..];
+            return (&quoted[..end], rest.strip_prefix(',').unwrap_or(rest));
+        }
+    }
+    match input.find(',') {
+        Some(pos) => (&input[..pos], &input[pos + 1..]),
+        None => (input, ""),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn feed_all(parser: &mut IpdParser, input: &[u8]) -> Vec<u8, 64> {
+        let mut payload = Vec::new();
+        for byte in input {
+            if let Some(byte) = parser.feed(*byte) {
+                payload.push(byte).unwrap();
+            }
+        }
+        payload
+    }
+
+    fn sample_config() -> Esp8266Config {
+        Esp8266Config {
+            mode: 1,
+            mux: true,
+            baud: 115200,
+            port: 8080,
+            timeout: 3000,
+            ssid: String::from_str("home").unwrap(),
+            password: String::from_str("secret").unwrap(),
+            protocol: String::from_str("UDP").unwrap(),
+            host: String::from_str("192.168.1.20").unwrap(),
+        }
+    }
+
+    #[test]
+    fn config_round_trip() {
+        let config = sample_config();
+        let mut buf = [0_u8; CONFIG_BYTES];
+        let len = config.to_bytes(&mut buf);
+        assert_eq!(Esp8266Config::from_bytes(&buf[..len]).unwrap(), config);
+    }
+
+    #[test]
+    fn config_rejects_corrupted_checksum() {
+        let mut buf = [0_u8; CONFIG_BYTES];
+        let len = sample_config().to_bytes(&mut buf);
+        buf[5] ^= 0x01;
+        assert!(matches!(Esp8266Config::from_bytes(&buf[..len]), Err(Error::InvalidParameter)));
+    }
+
+    #[test]
+    fn status_line_skips_the_echo() {
+        assert_eq!(status_line("AT\r\r\n\r\nOK"), "OK");
+        assert_eq!(status_line("\r\nOK\r\n"), "OK");
+        assert_eq!(status_line(""), "");
+    }
+
+    #[test]
+    fn trace_keeps_the_newest_bytes_in_order() {
+        let mut trace = Trace::<8>::new();
+        trace.record(b"AT\r\n");
+        trace.record(b"OK\r\n");
+        assert_eq!(trace.as_slices(), (&b"AT\r\nOK\r\n"[..], &b""[..]));
+        trace.record(b"AT");
+        assert_eq!(trace.as_slices(), (&b"\r\nOK\r\n"[..], &b"AT"[..]));
+        trace.record(b"0123456789");
+        assert_eq!(trace.as_slices(), (&b"2345"[..], &b"6789"[..]));
+    }
+
+    #[test]
+    fn ipd_single_connection() {
+        let mut parser = IpdParser::new("+IPD,");
+        assert_eq!(feed_all(&mut parser, b"\r\n+IPD,5:hello\r\n").as_slice(), b"hello");
+        assert_eq!(parser.link_id, None);
+        assert!(!parser.in_frame());
+    }
+
+    #[test]
+    fn ipd_mux_keeps_link_id() {
+        let mut parser = IpdParser::new("+IPD,");
+        assert_eq!(feed_all(&mut parser, b"+IPD,3,4:ping").as_slice(), b"ping");
+        assert_eq!(parser.link_id, Some(3));
+    }
+
+    #[test]
+    fn ipd_colon_in_payload() {
+        let mut parser = IpdParser::new("+IPD,");
+        assert_eq!(feed_all(&mut parser, b"+IPD,0,8:a:b+IPD,").as_slice(), b"a:b+IPD,");
+        assert_eq!(feed_all(&mut parser, b"+IPD,0,2:ok").as_slice(), b"ok");
+    }
+
+    #[test]
+    fn ipd_oversized_header_is_ignored() {
+        let mut parser = IpdParser::new("+IPD,");
+        let payload = feed_all(&mut parser, b"+IPD,0,123456789012345678901234567890:x\r\n+IPD,1,1:y");
+        assert_eq!(payload.as_slice(), b"y");
+        assert_eq!(parser.link_id, Some(1));
+    }
+
+    #[test]
+    fn ipd_reports_lines_between_frames() {
+        let mut parser = IpdParser::new("+IPD,");
+        let mut lines = 0;
+        for byte in b"+IPD,0,2:ab0,CLOSED\r\n" {
+            parser.feed(*byte);
+            if let Some(line) = parser.line() {
+                assert_eq!(line, "0,CLOSED");
+                lines += 1;
+            }
         }
+        assert_eq!(lines, 1);
     }
 }
@@ -0,0 +1,116 @@
+use core::fmt::Write;
+use core::str::FromStr;
+use embedded_hal::digital::v2::OutputPin;
+use heapless::{String, Vec};
+
+use crate::{next_field, Error, ESP8266, ESP_TX_BUFF_SIZE};
+
+#[derive(Debug, Clone)]
+pub struct SntpConfig {
+    pub enabled: bool,
+    pub timezone: i16,
+    pub servers: Vec<String<64>, 3>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DateTime {
+    pub year: u16,
+    pub month: u8,
+    pub day: u8,
+    pub hour: u8,
+    pub minute: u8,
+    pub second: u8,
+}
+
+impl FromStr for DateTime {
+    type Err = Error;
+
+    // asctime style, e.g. "Thu Aug 04 14:48:05 2016"
+    fn from_str(input: &str) -> Result<Self, Self::Err> {
+        const MONTHS: [&str; 12] = ["Jan", "Feb", "Mar", "Apr", "May", "Jun", "Jul", "Aug", "Sep", "Oct", "Nov", "Dec"];
+        let mut parts = input.split_whitespace();
+        let _weekday = parts.next().ok_or(Error::Failure)?;
+        let month = parts.next().ok_or(Error::Failure)?;
+        let month = MONTHS.iter().position(|name| *name == month).ok_or(Error::Failure)? as u8 + 1;
+        let day = parts.next().ok_or(Error::Failure)?.parse().map_err(|_| Error::Failure)?;
+        let mut clock = parts.next().ok_or(Error::Failure)?.split(':');
+        let mut clock_field = || clock.next().and_then(|field| field.parse::<u8>().ok()).ok_or(Error::Failure);
+        let (hour, minute, second) = (clock_field()?, clock_field()?, clock_field()?);
+        let year = parts.next().ok_or(Error::Failure)?.parse().map_err(|_| Error::Failure)?;
+        if !(1..=31).contains(&day) || hour > 23 || minute > 59 || second > 60 {
+            return Err(Error::Failure);
+        }
+        Ok(Self { year, month, day, hour, minute, second })
+    }
+}
+
+impl<'a, USART, TIM, PIN, const FREQ: u32, const TRACE: usize> ESP8266<'a, USART, TIM, PIN, FREQ, TRACE>
+where
+    USART: stm32f1xx_hal::serial::Instance,
+    TIM: stm32f1xx_hal::timer::Instance,
+    PIN: OutputPin,
+{
+    // timezone is an hour offset (-12..14) or HHmm on firmware that accepts it, up to 3 servers
+    pub fn enable_sntp(&mut self, timezone: i16, servers: &[&str]) -> Result<bool, Error> {
+        if !(-1200..=1400).contains(&timezone) || servers.len() > 3 {
+            return Err(Error::InvalidParameter);
+        }
+        let mut command = String::<ESP_TX_BUFF_SIZE>::new();
+        write!(command, "AT+CIPSNTPCFG=1,{}", timezone).ok();
+        for server in servers {
+            write!(command, ",\"{}\"", server).map_err(|_| Error::InvalidParameter)?;
+        }
+        self.send(&command);
+        let receive = self.recv_string_with_flag(self.ok(), 3000_u32)?;
+        Ok(receive.ends_with(self.ok()))
+    }
+
+    pub fn disable_sntp(&mut self) -> Result<bool, Error> {
+        self.send("AT+CIPSNTPCFG=0");
+        let receive = self.recv_string_with_flag(self.ok(), 3000_u32)?;
+        Ok(receive.ends_with(self.ok()))
+    }
+
+    pub fn get_sntp_config(&mut self) -> Result<SntpConfig, Error> {
+        self.send("AT+CIPSNTPCFG?");
+        let receive = self.recv_string_with_flag(self.ok(), 3000_u32)?;
+        for line in receive.lines() {
+            // +CIPSNTPCFG:<enable>,<timezone>[,<server1>[,<server2>[,<server3>]]]
+            if let Some(data_str) = line.strip_prefix("+CIPSNTPCFG:") {
+                let (enable, rest) = next_field(data_str);
+                let (timezone, mut rest) = next_field(rest);
+                let mut config = SntpConfig {
+                    enabled: enable == "1",
+                    // disabled SNTP may be reported without a timezone
+                    timezone: timezone.parse().unwrap_or(0),
+                    servers: Vec::new(),
+                };
+                while !rest.is_empty() {
+                    let (server, next) = next_field(rest);
+                    if !server.is_empty() {
+                        let server = String::from_str(server).map_err(|_| Error::Failure)?;
+                        config.servers.push(server).map_err(|_| Error::Failure)?;
+                    }
+                    rest = next;
+                }
+                return Ok(config);
+            }
+        }
+        Err(Error::NoResponse)
+    }
+
+    pub fn sntp_time(&mut self) -> Result<String<32>, Error> {
+        self.send("AT+CIPSNTPTIME?");
+        let receive = self.recv_string_with_flag(self.ok(), 3000_u32)?;
+        for line in receive.lines() {
+            if let Some(data_str) = line.strip_prefix("+CIPSNTPTIME:") {
+                return String::from_str(data_str.trim()).map_err(|_| Error::Failure);
+            }
+        }
+        Err(Error::NoResponse)
+    }
+
+    pub fn current_datetime(&mut self) -> Result<DateTime, Error> {
+        self.sntp_time()?.parse()
+    }
+}